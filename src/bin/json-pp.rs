@@ -1,37 +1,37 @@
-use std::{io::{Write, BufWriter, stdin, stdout}, fs::read_to_string};
+use std::io::{Write, BufWriter, stdin, stdout};
 
 use anyhow::{Result, Context, anyhow, bail};
 use jzon::codegen::{Generator, PrettyWriterGenerator};
-use ndjson_updater::{tempfile::named_tempfile_for, io_read_to_string::io_read_to_string};
+use ndjson_updater::{tempfile::ReplacingTempfile, io_read_to_string::io_read_to_string,
+                     compressed_io::{read_to_string, decoding_reader, Codec, CodecWriter}};
 
 
 fn json_pp(input: &str, outp: &mut impl Write) -> Result<()> {
     let entry = jzon::parse(input)?;
     let mut jsonwriter = PrettyWriterGenerator::new(outp, 2);
     jsonwriter.write_json(&entry)?;
-    jsonwriter.get_writer().write(b"\n")?;
+    jsonwriter.get_writer().write_all(b"\n")?;
     Ok(())
 }
 
 fn inplace_json_pp(path: &str) -> Result<()> {
     (|| -> Result<_> {
         let input = read_to_string(path)?;
-        let mut tmp = named_tempfile_for(path)?;
+        let mut tmp = ReplacingTempfile::new(path)?;
         {
-            let mut outp = BufWriter::new(tmp.as_file_mut());
+            let mut outp = CodecWriter::new(Codec::from_extension(path),
+                                            BufWriter::new(tmp.as_file_mut()))?;
             json_pp(&input, &mut outp)?;
-            outp.flush()?;
-            // fsync (makes it slower but safe):
-            outp.get_mut().sync_data()?;
+            outp.finish()?;
         }
-        std::fs::rename(tmp.path(), path)?;
+        tmp.commit(false)?;
         Ok(())
     })().with_context(|| anyhow!("processing file {path:?}"))
 }
 
 fn pipeline_json_pp() -> Result<()> {
     (|| -> Result<_> {
-        let input = io_read_to_string(stdin())?;
+        let input = io_read_to_string(decoding_reader(stdin())?)?;
         let mut outp = BufWriter::new(stdout());
         json_pp(&input, &mut outp)
     })().with_context(|| anyhow!("processing stdin to stdout"))
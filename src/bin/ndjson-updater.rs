@@ -1,100 +1,253 @@
-use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::{Write, BufReader, BufRead};
+use std::io::{Write, BufWriter};
 
 use anyhow::{anyhow, Context, Result, bail};
-use jzon::JsonValue;
-use jzon::codegen::{Generator, WriterGenerator};
-use ndjson_updater::easyjson::{EasyJsonValue, EasyObject};
+use ndjson_updater::compressed_io::{open_reader, create_writer, Codec, CodecWriter};
+use ndjson_updater::column_mapping::{ColumnMapping, ColumnType, MappingConfig};
+use ndjson_updater::jsonpath::JsonPath;
+use ndjson_updater::ndjson_update::{Updater, Source, MissingRowPolicy, UnusedRowPolicy,
+                                    ConflictPolicy, RowUsage, ChangeStats};
+use ndjson_updater::parallel_lines::process_lines_ordered;
+use ndjson_updater::tempfile::ReplacingTempfile;
+use ndjson_updater::tsv::Tsv;
 
-#[allow(unused, non_snake_case)]
-#[derive(Debug, serde::Deserialize)]
-struct TsvEntry {
-    gisaid_epi_isl: String,
-    pango_lineage: String,
-    date: String,
-    region: String,
-    country: String,
-    division: String,
-    unsorted_date: String,
-    age: String,
-    qc_value: String,
-    nucleotideInsertions: String,
-    aminoAcidInsertions: String,
-    test_boolean_column: String
-}
-
-fn read_tsv_by_gisaid_epi_isl(path: &str) -> Result<HashMap<String, TsvEntry>> {
-    let inp = BufReader::new(File::open(path)?);
-    
-    let mut rdr = csv::ReaderBuilder::new()
-        .delimiter(b'\t')
-        .has_headers(true)
-        // .trim(csv::Trim::All)
-        .from_reader(inp);
-    
-    let mut entries = HashMap::new();
-    for r in rdr.deserialize() {
-        let r: TsvEntry = r?;
-        if let Some(old)= entries.insert(r.gisaid_epi_isl.clone(), r) {
-            bail!("duplicate entry in {path:?}: {old:?}");
+/// The mapping used when no `--mapping` file is given.
+fn default_mapping() -> MappingConfig {
+    MappingConfig::with_columns(vec![
+        ColumnMapping {
+            column: "test_boolean_column".into(),
+            field: "test_boolean_column".into(),
+            column_type: ColumnType::Bool,
         }
-    }
-    
-    Ok(entries)
-}
-
-fn parse_csv_boolean(valstr: &str) -> Result<JsonValue> {
-    Ok(match valstr {
-        "true" => true.into(),
-        "false" => false.into(),
-        "" => JsonValue::Null,
-        _=> bail!("invalid value for 'test_boolean_column': {valstr:?}")
-    })
+    ])
 }
 
 fn main() -> Result<()> {
     let mut args = std::env::args();
     let cmd = args.next().unwrap();
-    let args: Vec<_> = args.collect();
-    if let [tsvpath, inpath, outpath] = &*args {
-        let tsventries = read_tsv_by_gisaid_epi_isl(&tsvpath)?;
 
-        let mut inp = BufReader::new(File::open(&inpath)?);
-        let mut outp = File::create(&outpath)?;
-        let mut jsonwriter = WriterGenerator::new(&mut outp);
+    let mut opt_mapping = None;
+    let mut opt_tsv_key = None;
+    let mut opt_json_key = None;
+    let mut opt_target = None;
+    let mut opt_sources = Vec::new();
+    let mut opt_conflicts = ConflictPolicy::Error;
+    let mut opt_missing_row = MissingRowPolicy::Error;
+    let mut opt_unused_rows = UnusedRowPolicy::Report;
+    let mut opt_unused_keys_out = None;
+    let mut opt_inplace = false;
+    let mut opt_backup = false;
+    let mut opt_preserve_formatting = false;
+    let mut opt_dry_run = false;
+    let mut opt_diff_out = None;
+    let mut opt_threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut positional = Vec::new();
 
-        let mut used_gisaid_epi_isl = HashSet::new();
+    while let Some(arg) = args.next() {
+        let mut optarg = || args.next().ok_or_else(
+            || anyhow!("{cmd}: missing argument after {arg:?}"));
+        match &*arg {
+            "--mapping" | "-m" =>
+                opt_mapping = Some(optarg()?),
+            "--tsv-key" =>
+                opt_tsv_key = Some(optarg()?),
+            "--json-key" =>
+                opt_json_key = Some(JsonPath::parse(&optarg()?)?),
+            "--target" =>
+                opt_target = Some(JsonPath::parse(&optarg()?)?),
+            "--source" | "-s" => {
+                let tsvpath = optarg()?;
+                let mappingpath = optarg()?;
+                opt_sources.push((tsvpath, mappingpath));
+            }
+            "--conflicts" =>
+                opt_conflicts = optarg()?.parse()?,
+            "--missing-row" =>
+                opt_missing_row = optarg()?.parse()?,
+            "--unused-rows" =>
+                opt_unused_rows = optarg()?.parse()?,
+            "--unused-keys-out" =>
+                opt_unused_keys_out = Some(optarg()?),
+            "--inplace" | "-i" =>
+                opt_inplace = true,
+            "--backup" =>
+                opt_backup = true,
+            "--preserve-formatting" | "-p" =>
+                opt_preserve_formatting = true,
+            "--dry-run" | "-n" =>
+                opt_dry_run = true,
+            "--diff-out" =>
+                opt_diff_out = Some(optarg()?),
+            "--threads" | "-j" =>
+                opt_threads = optarg()?.parse()?,
+            "--" => {
+                positional.extend(args);
+                break;
+            }
+            _ =>
+                if arg.starts_with('-') {
+                    bail!("{cmd}: unknown option {arg:?}")
+                } else {
+                    positional.push(arg)
+                }
+        }
+    }
 
-        let mut line = String::new();
-        let mut lineno = 0;
-        (|| -> Result<_> {
-            while inp.read_line(&mut line)? != 0 {
-                lineno += 1;
-                let mut entry = jzon::parse(&line)?;
-                let metadata = entry.object_mut()?.xget_mut("metadata")?.object_mut()?;
-                let id = metadata.xget("gisaid_epi_isl")?.str()?;
+    let dry_run = opt_dry_run || opt_diff_out.is_some();
+    // (tsvpath, inpath, outpath), no outpath for dry runs
+    let paths = match (&*positional, opt_inplace, dry_run) {
+        ([tsvpath, path], true, _) => Some((Some(tsvpath), path, Some(path))),
+        ([path], true, _) => Some((None, path, Some(path))),
+        ([tsvpath, inpath], false, true) => Some((Some(tsvpath), inpath, None)),
+        ([inpath], false, true) => Some((None, inpath, None)),
+        ([tsvpath, inpath, outpath], false, false) => Some((Some(tsvpath), inpath, Some(outpath))),
+        ([inpath, outpath], false, false) => Some((None, inpath, Some(outpath))),
+        _ => None
+    };
+    if let Some((tsvpath, inpath, outpath)) = paths {
+        // (tsvpath, mapping) for all sources
+        let mut source_configs = Vec::new();
+        if let Some(tsvpath) = tsvpath {
+            let mut mapping = match &opt_mapping {
+                Some(path) => MappingConfig::from_file(path)?,
+                None => default_mapping(),
+            };
+            if let Some(tsv_key) = opt_tsv_key {
+                mapping.tsv_key = tsv_key;
+            }
+            if let Some(json_key) = opt_json_key {
+                mapping.json_key = json_key;
+            }
+            if let Some(target) = opt_target {
+                mapping.target = target;
+            }
+            source_configs.push((tsvpath.clone(), mapping));
+        } else if opt_mapping.is_some() || opt_tsv_key.is_some()
+            || opt_json_key.is_some() || opt_target.is_some() {
+            bail!("{cmd}: --mapping, --tsv-key, --json-key and --target only apply to \
+                   the tsvpath argument, use --source otherwise")
+        }
+        for (tsvpath, mappingpath) in opt_sources {
+            source_configs.push((tsvpath, MappingConfig::from_file(&mappingpath)?));
+        }
+        if source_configs.is_empty() {
+            bail!("{cmd}: no TSV file given")
+        }
 
-                let tsventry = tsventries.get(id).ok_or_else(
-                    || anyhow!("unknown \"gisaid_epi_isl\" value {id:?}"))?;
+        let tsvs = source_configs.iter().map(|(tsvpath, _)| Tsv::from_file(tsvpath))
+            .collect::<Result<Vec<_>>>()?;
+        let sources = tsvs.iter().zip(source_configs).map(
+            |(tsv, (_, mapping))| Source::new(tsv, mapping))
+            .collect::<Result<Vec<_>>>()?;
+        let mut updater = Updater::new(sources, opt_missing_row, opt_conflicts)?;
+        updater.set_track_changes(dry_run);
+        let updater = updater;
+        let sources = updater.sources();
 
-                if used_gisaid_epi_isl.contains(id) {
-                    bail!("gisaid_epi_isl {id:?} used multiple times")
-                }
-                used_gisaid_epi_isl.insert(id.to_string());
+        let inp = open_reader(inpath)?;
+        // Written to a tempfile that only replaces `outpath` once
+        // everything succeeded
+        let mut tmp = match outpath {
+            Some(outpath) if ! dry_run => Some((outpath, ReplacingTempfile::new(outpath)?)),
+            _ => None
+        };
+        let mut outp = match &mut tmp {
+            Some((outpath, tmp)) => Some(CodecWriter::new(Codec::from_extension(outpath),
+                                                          BufWriter::new(tmp.as_file_mut()))?),
+            None => None
+        };
+        let mut diff_outp = match &opt_diff_out {
+            Some(path) => Some(create_writer(path)?),
+            None => None
+        };
 
-                metadata.insert("test_boolean_column",
-                                parse_csv_boolean(&tsventry.test_boolean_column)?);
+        let mut row_usage = RowUsage::new(sources);
+        let mut change_stats = ChangeStats::new(sources);
+
+        process_lines_ordered(
+            inp, opt_threads,
+            |_lineno, line| {
+                if opt_preserve_formatting {
+                    if dry_run {
+                        return Ok((updater.check_line(line)?, None))
+                    }
+                    return updater.update_line(line)
+                }
+                let mut entry = jzon::parse(line)?;
+                let update = updater.update_record(&mut entry)?;
+                let output = if update.is_written() && ! dry_run {
+                    Some(entry.dump())
+                } else {
+                    None
+                };
+                Ok((update, output))
+            },
+            |_lineno, (update, output)| {
+                change_stats.add(&update);
+                if let Some(diff_outp) = &mut diff_outp {
+                    update.write_diff(sources, diff_outp)?;
+                }
+                // Here rather than in the workers, to see the records
+                // in input order
+                row_usage.add(sources, &update)?;
+                if let (Some(outp), Some(output)) = (&mut outp, output) {
+                    outp.write_all(output.as_bytes())?;
+                    outp.write_all(b"\n")?;
+                }
+                Ok(())
+            })?;
+        outp.map(|outp| outp.finish()).transpose()?;
+        if let Some(diff_outp) = diff_outp {
+            diff_outp.finish()?;
+        }
+        if dry_run {
+            change_stats.print(sources, std::io::stderr())?;
+        }
 
-                jsonwriter.write_json(&entry)?;
-                jsonwriter.get_writer().write(b"\n")?;
-                line.clear();
+        for (source, num_missing) in sources.iter().zip(&row_usage.num_missing) {
+            if *num_missing > 0 {
+                eprintln!("{cmd}: {num_missing} records without a row in {:?}",
+                          source.tsv.path);
             }
-            Ok(())
-        })().with_context(|| anyhow!("on line {lineno}"))?;
+        }
+
+        if let Some(path) = &opt_unused_keys_out {
+            (|| -> Result<_> {
+                row_usage.write_unused_keys(sources, BufWriter::new(File::create(path)?))
+            })().with_context(|| anyhow!("writing unused keys to {path:?}"))?;
+        }
+        row_usage.check_unused(sources, opt_unused_rows, std::io::stderr())?;
+
+        if let Some((outpath, tmp)) = tmp {
+            tmp.commit(opt_backup).with_context(|| anyhow!("writing {outpath:?}"))?;
+        }
     } else {
-        bail!("usage: {cmd} tsvpath inpath outpath");
+        bail!("usage: {cmd} [options] [tsvpath] inpath outpath\n       \
+               {cmd} [options] --inplace [tsvpath] path\n       \
+               {cmd} [options] --dry-run|--diff-out path [tsvpath] inpath\n\
+               options:\n  \
+               --mapping | -m path    column mapping config (JSON) for tsvpath\n  \
+               --tsv-key column       TSV column to join on, for tsvpath\n  \
+               --json-key path        path of the join key in the records, for tsvpath\n  \
+               --target path          path of the object receiving the fields, for tsvpath\n  \
+               --source | -s tsvpath mappingpath\n                         \
+               add a further TSV file with its mapping config, repeatable\n  \
+               --conflicts policy     two sources setting a field to different\n                         \
+               values: error|first|last (in the order given, tsvpath first)\n  \
+               --missing-row policy   record without TSV row: error|skip|pass|null\n  \
+               --unused-rows policy   TSV rows matching no record: error|report|ignore\n  \
+               --unused-keys-out path write the keys of unused TSV rows to path\n                         \
+               (prefixed with the TSV path and a tab if there are several)\n  \
+               --inplace | -i         replace the input file with the output\n  \
+               --backup               keep the replaced file as *.bak\n  \
+               --preserve-formatting | -p\n                         \
+               only replace the mapped fields in the record text, copy the\n                         \
+               rest unchanged (faster, but does not validate the records)\n  \
+               --dry-run | -n         don't write the output, report the changes\n  \
+               --diff-out path        write the changes as ndjson to path (implies\n                         \
+               --dry-run), with keys key, field, old (if present), new\n  \
+               --threads | -j n       number of worker threads (default: number of cores)");
     }
-    
+
     Ok(())
 }
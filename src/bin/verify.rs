@@ -1,184 +1,219 @@
-use std::convert::{TryFrom, TryInto};
-use std::{collections::HashMap, io::stdout};
-use std::fmt::Debug;
-use std::fs::File;
-use std::io::BufReader;
+use std::collections::HashMap;
 
 use anyhow::{Result, bail};
-use itertools::Itertools;
-use ndjson_updater::pangolineage::PangoLineage;
-use ndjson_updater::{groupby::{group_by, print_group_sizes}, lineagelist_index::LineageAliases};
-
-#[allow(unused, non_snake_case)]
-#[derive(Debug, serde::Deserialize)]
-struct TsvEntry {
-    gisaid_epi_isl: String,
-    pango_lineage: String,
-    date: String,
-    region: String,
-    country: String,
-    division: String,
-    unsorted_date: String,
-    age: String,
-    qc_value: String,
-    nucleotideInsertions: String,
-    aminoAcidInsertions: String,
-    test_boolean_column: String
+use ndjson_updater::query::Query;
+use ndjson_updater::query_engine::{QueryEngine, Table};
+use ndjson_updater::test_cases::{TestCase, test_case_paths};
+use ndjson_updater::tsv::Tsv;
+use ndjson_updater::tsv_value::{ColumnType, TsvValueParser};
+use ndjson_updater::lineagelist_index::LineageAliases;
+
+fn read_table(tsv_path: &str) -> Result<Table> {
+    let mut column_types = HashMap::new();
+    column_types.insert("pango_lineage".into(), ColumnType::Lineage);
+    column_types.insert("test_boolean_column".into(), ColumnType::Bool);
+    let tsv = Tsv::from_file(tsv_path)?;
+    tsv.index_by("gisaid_epi_isl")?;
+    Table::from_tsv(&tsv, &TsvValueParser::default(), &column_types)
 }
 
-fn read_tsv_by_gisaid_epi_isl(path: &str) -> Result<HashMap<String, TsvEntry>> {
-    let inp = BufReader::new(File::open(path)?);
-    
-    let mut rdr = csv::ReaderBuilder::new()
-        .delimiter(b'\t')
-        .has_headers(true)
-        // .trim(csv::Trim::All)
-        .from_reader(inp);
-    
-    let mut entries = HashMap::new();
-    for r in rdr.deserialize() {
-        let r: TsvEntry = r?;
-        if let Some(old)= entries.insert(r.gisaid_epi_isl.clone(), r) {
-            bail!("duplicate entry in {path:?}: {old:?}");
+/// Run the test cases in `dir`, print a pass/fail table; returns
+/// whether all passed. With `bless`, failing expectations are
+/// replaced with the actual results.
+fn run_test_cases(engine: &QueryEngine, dir: &str, bless: bool) -> Result<bool> {
+    let paths = test_case_paths(dir.as_ref())?;
+    let (mut passed, mut failed, mut blessed) = (0, 0, 0);
+    for path in &paths {
+        let file_name = path.file_name().expect("from read_dir").to_string_lossy();
+        let outcome = TestCase::from_file(path).and_then(|mut test_case| {
+            let result = engine.run(&test_case.query)?;
+            let diff = test_case.diff(&result);
+            if bless && ! diff.is_empty() {
+                test_case.bless(&result)?;
+            }
+            Ok((test_case.name, diff))
+        });
+        match outcome {
+            Ok((name, diff)) if diff.is_empty() => {
+                passed += 1;
+                println!("PASS   {file_name}  {name}");
+            }
+            Ok((name, diff)) => {
+                let status = if bless {
+                    blessed += 1;
+                    "BLESS"
+                } else {
+                    failed += 1;
+                    "FAIL"
+                };
+                println!("{status:6} {file_name}  {name}");
+                for line in diff {
+                    println!("         {line}");
+                }
+            }
+            Err(e) => {
+                failed += 1;
+                println!("ERROR  {file_name}  {e:#}");
+            }
         }
     }
-    
-    Ok(entries)
+    println!("{passed} passed, {failed} failed{}",
+             if bless { format!(", {blessed} blessed") } else { String::new() });
+    Ok(failed == 0)
 }
 
-
 fn main() -> Result<()> {
     let mut args = std::env::args();
     let cmd = args.next().unwrap();
     let args: Vec<_> = args.collect();
 
-    if let [lineage_data_json_path, tsv_path] = &*args {
-
-        let lineage_aliases = LineageAliases::from_file(&lineage_data_json_path)?;
-        // lineage_aliases.print(stdout())?;
+    if let [flag, lineage_data_json_path, alias_key_json_path] = &*args {
+        if flag == "--cross-check-aliases" {
+            let from_data = LineageAliases::from_file(lineage_data_json_path)?;
+            let from_key = LineageAliases::from_alias_key_file(alias_key_json_path)?;
+            let disagreements = from_key.cross_check(
+                &from_data, (alias_key_json_path, lineage_data_json_path));
+            for msg in &disagreements {
+                println!("{msg}");
+            }
+            eprintln!("{cmd}: {} disagreements", disagreements.len());
+            return Ok(())
+        }
+    }
 
-        let tsventries = read_tsv_by_gisaid_epi_isl(&tsv_path)?;
+    let test_cases_args = match &*args {
+        [flag, lineage_data_json_path, tsv_path, dir] if flag == "--test-cases" =>
+            Some((false, lineage_data_json_path, tsv_path, dir)),
+        [flag, bless, lineage_data_json_path, tsv_path, dir]
+            if flag == "--test-cases" && bless == "--bless" =>
+            Some((true, lineage_data_json_path, tsv_path, dir)),
+        _ => None
+    };
+    if let Some((bless, lineage_data_json_path, tsv_path, dir)) = test_cases_args {
+        let lineage_aliases = LineageAliases::from_file(lineage_data_json_path)?;
+        let table = read_table(tsv_path)?;
+        let engine = QueryEngine::new(&table, &lineage_aliases)?;
+        if ! run_test_cases(&engine, dir, bless)? {
+            bail!("there were failing test cases")
+        }
+        return Ok(())
+    }
 
-        let by_test_boolean_column = group_by(
-            tsventries.values(),
-            |e| { &e.test_boolean_column },
-            |e| { &e.gisaid_epi_isl })?;
-        print_group_sizes(&by_test_boolean_column);
+    let aliases_and_tsv_path = match &*args {
+        [flag, alias_key_json_path, tsv_path] if flag == "--alias-key" =>
+            Some((LineageAliases::from_alias_key_file(alias_key_json_path)?, tsv_path)),
+        [lineage_data_json_path, tsv_path] =>
+            Some((LineageAliases::from_file(lineage_data_json_path)?, tsv_path)),
+        _ => None
+    };
+    if let Some((lineage_aliases, tsv_path)) = aliases_and_tsv_path {
 
-        dbg!(tsventries.values().filter(|e| e.test_boolean_column == "true").count());
-        dbg!(tsventries.values().filter(|e| e.test_boolean_column == "false").count());
-        dbg!(tsventries.values().filter(|e| e.test_boolean_column == "").count());
+        // lineage_aliases.print(stdout())?;
 
-        let lineage_is_sublineage_of = |lin: &str| -> Result<_> {
-            let ancestor = lineage_aliases.canonicalize(PangoLineage::try_from(lin)?);
-            // dbg!(&ancestor);
-            let lineage_aliases = &lineage_aliases;
-            Ok(move |e: &&TsvEntry| {
-                let lin = e.pango_lineage.as_str();
-                if lin.is_empty() {
-                    eprintln!("tsv entry with empty pango_lineage {e:?}");
-                    false
-                } else {
-                    let lin = lin.try_into().unwrap();
-                    // dbg!(&lin);
-                    let lincan = lineage_aliases.canonicalize(lin);
-                    // dbg!(&lincan);
-                    ancestor.is_ancestor_of(&lincan, true)
-                }
-            })
-        };
+        let table = read_table(tsv_path)?;
+        let engine = QueryEngine::new(&table, &lineage_aliases)?;
 
-        let is_sublineage_of_b_1 = lineage_is_sublineage_of("B.1")?;
-        dbg!(tsventries.values().filter(|e| {
-            e.test_boolean_column == "false" && is_sublineage_of_b_1(e)
-        }).count());
+        let query = Query::parse(r#"{
+            "action": {
+              "type": "Aggregated",
+              "groupByFields": ["test_boolean_column"]
+            },
+            "filterExpression": {
+              "type": "True"
+            }
+        }"#)?;
+        println!("{}", engine.run(&query)?.pretty(2));
 
-        dbg!(tsventries.values().filter(|e| {
-            e.test_boolean_column == "" || is_sublineage_of_b_1(e)
-        }).count());
+        let count = |filter: &str| -> Result<String> {
+            let query = Query::parse(&format!(
+                r#"{{"action": {{"type": "Aggregated"}}, "filterExpression": {filter}}}"#))?;
+            Ok(engine.run(&query)?.dump())
+        };
+        let test_boolean = |value: &str| format!(
+            r#"{{"type": "BooleanEquals", "column": "test_boolean_column", "value": {value}}}"#);
+        let sublineages_of = |lineage: &str| format!(
+            r#"{{"type": "PangoLineage", "column": "pango_lineage", "value": "{lineage}",
+                 "includeSublineages": true}}"#);
+        for value in ["true", "false", "null"] {
+            println!("test_boolean_column = {value}: {}", count(&test_boolean(value))?);
+        }
+        println!("test_boolean_column = false and B.1*: {}", count(&format!(
+            r#"{{"type": "And", "children": [{}, {}]}}"#,
+            test_boolean("false"), sublineages_of("B.1")))?);
+        println!("test_boolean_column = null or B.1*: {}", count(&format!(
+            r#"{{"type": "Or", "children": [{}, {}]}}"#,
+            test_boolean("null"), sublineages_of("B.1")))?);
         // should give 97
-
-        let is_sublineage_of_b_1_1 = lineage_is_sublineage_of("B.1.1")?;
-        dbg!(tsventries.values().filter(|e| {
-            e.test_boolean_column == "" || is_sublineage_of_b_1_1(e)
-        }).count());
-
-
-        // {
-        //   "testCaseName": "pango lineage B.1.1.7 including sublineages",
-        //   "query": {
-        //     "action": {
-        //       "type": "Aggregated"
-        //     },
-        //     "filterExpression": {
-        //       "type": "PangoLineage",
-        //       "column": "pango_lineage",
-        //       "value": "B.1.1.7",
-        //       "includeSublineages": true
-        //     }
-        //   },
-        //   "expectedQueryResult": [
-        //     {
-        //       "count": 51
-        //     }
-        //   ]
-        // }
-        dbg!(tsventries.values().filter(lineage_is_sublineage_of("B.1.1.7")?).count());
-        // count() = 86
-
-        // {
-        //     "action": {
-        //       "type": "Details",
-        //       "fields": ["pango_lineage"],
-        //       "orderByFields": [
-        //         {
-        //           "field": "pango_lineage",
-        //           "order": "ascending"
-        //         }
-        //       ]
-        //     },
-        //     "filterExpression": {
-        //       "type": "PangoLineage",
-        //       "column": "pango_lineage",
-        //       "value": "B.1.1",
-        //       "includeSublineages": true
-        //     }
-        // }
-        dbg!(tsventries.values()
-             .filter(lineage_is_sublineage_of("B.1.1")?)
-             .map(|e| &e.pango_lineage)
-             .sorted()
-             .collect::<Vec<_>>());
+        println!("test_boolean_column = null or B.1.1*: {}", count(&format!(
+            r#"{{"type": "Or", "children": [{}, {}]}}"#,
+            test_boolean("null"), sublineages_of("B.1.1")))?);
+
+        // testCaseName: "pango lineage B.1.1.7 including sublineages"
+        let query = Query::parse(r#"{
+            "action": {
+              "type": "Aggregated"
+            },
+            "filterExpression": {
+              "type": "PangoLineage",
+              "column": "pango_lineage",
+              "value": "B.1.1.7",
+              "includeSublineages": true
+            }
+        }"#)?;
+        println!("{}", engine.run(&query)?.pretty(2));
+        // expectedQueryResult: [{"count": 51}]; the hand-written filter
+        // gave 86
+
+        let query = Query::parse(r#"{
+            "action": {
+              "type": "Details",
+              "fields": ["pango_lineage"],
+              "orderByFields": [
+                {
+                  "field": "pango_lineage",
+                  "order": "ascending"
+                }
+              ]
+            },
+            "filterExpression": {
+              "type": "PangoLineage",
+              "column": "pango_lineage",
+              "value": "B.1.1",
+              "includeSublineages": true
+            }
+        }"#)?;
+        println!("{}", engine.run(&query)?.pretty(2));
 
         if false {
-            let by_pango_lineage = group_by(
-                tsventries.values(),
-                |e| { &e.pango_lineage },
-                |e| { &e.gisaid_epi_isl })?;
-            print_group_sizes(&by_pango_lineage);
+            let query = Query::parse(r#"{
+                "action": {
+                  "type": "Aggregated",
+                  "groupByFields": ["pango_lineage"]
+                },
+                "filterExpression": {
+                  "type": "True"
+                }
+            }"#)?;
+            println!("{}", engine.run(&query)?.pretty(2));
         }
 
-        // "query": {
-        //   "action": {
-        //     "type": "Details",
-        //     "fields": ["test_boolean_column", "gisaid_epi_isl"],
-        //     "orderByFields": ["gisaid_epi_isl"],
-        //     "limit": 10
-        //   },
-        //   "filterExpression": {
-        //     "type": "True"
-        //   }
-        // },
-        if false {
-            for key in tsventries.keys().sorted().take(10) {
-                let b = &tsventries.get(key).unwrap().test_boolean_column;
-                println!("{{\n  \"gisaid_epi_isl\": {key:?},\n  \"test_boolean_column\": {}\n}},",
-                         if b.is_empty() { "null" } else { b });
+        let query = Query::parse(r#"{
+            "action": {
+              "type": "Details",
+              "fields": ["test_boolean_column", "gisaid_epi_isl"],
+              "orderByFields": ["gisaid_epi_isl"],
+              "limit": 10
+            },
+            "filterExpression": {
+              "type": "True"
             }
-        }
-        
+        }"#)?;
+        println!("{}", engine.run(&query)?.pretty(2));
     } else {
-        bail!("usage: {cmd} lineage_data_json_path tsv_path");
+        bail!("usage: {cmd} lineage_data_json_path tsv_path\n       \
+               {cmd} --alias-key alias_key_json_path tsv_path\n       \
+               {cmd} --cross-check-aliases lineage_data_json_path alias_key_json_path\n       \
+               {cmd} --test-cases [--bless] lineage_data_json_path tsv_path test_cases_dir");
     }
     
     Ok(())
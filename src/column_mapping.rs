@@ -0,0 +1,165 @@
+//! Declarative mapping of TSV columns to fields in ndjson records,
+//! as read from a JSON config file like:
+//!
+//! ```json
+//! {
+//!   "tsv_key": "gisaid_epi_isl",
+//!   "json_key": "metadata.gisaid_epi_isl",
+//!   "target": "metadata",
+//!   "columns": [
+//!     {"column": "test_boolean_column", "field": "test_boolean_column", "type": "bool"},
+//!     {"column": "age", "field": "age", "type": "int"}
+//!   ]
+//! }
+//! ```
+//!
+//! The `type` is one of `bool`, `int`, `float`, `string`, `date`,
+//! `lineage` or `auto` (see `tsv_value`).
+//!
+//! `tsv_key` is the column in the TSV file that is joined with the
+//! value at `json_key` in the ndjson records; the mapped fields are
+//! stored in the object at `target`. The paths can be dotted or JSON
+//! Pointers (see `jsonpath`). The three default to the values shown
+//! above.
+//!
+//! Optionally, `"null_tokens": ["", "NA"]` sets the cell strings that
+//! are converted to null (default: only the empty string), and
+//! `"mode": "lenient"` selects lenient parsing instead of `"strict"`.
+
+use std::fs::read_to_string;
+
+use anyhow::{anyhow, bail, Context, Result};
+use kstring::KString;
+
+use crate::jsonpath::JsonPath;
+use crate::tsv_value::{ParseMode, TsvValueParser};
+pub use crate::tsv_value::ColumnType;
+
+
+/// Copy the TSV column `column` into the field `field`, converting it
+/// to `type`.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ColumnMapping {
+    pub column: String,
+    pub field: String,
+    #[serde(rename = "type")]
+    pub column_type: ColumnType,
+}
+
+fn default_tsv_key() -> String {
+    "gisaid_epi_isl".into()
+}
+
+fn default_json_key() -> JsonPath {
+    JsonPath::parse("metadata.gisaid_epi_isl").unwrap()
+}
+
+fn default_target() -> JsonPath {
+    JsonPath::parse("metadata").unwrap()
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MappingConfig {
+    #[serde(default = "default_tsv_key")]
+    pub tsv_key: String,
+    #[serde(default = "default_json_key")]
+    pub json_key: JsonPath,
+    #[serde(default = "default_target")]
+    pub target: JsonPath,
+    #[serde(default)]
+    pub null_tokens: Option<Vec<KString>>,
+    #[serde(default)]
+    pub mode: ParseMode,
+    pub columns: Vec<ColumnMapping>,
+}
+
+impl MappingConfig {
+    /// A config with the default key and target settings.
+    pub fn with_columns(columns: Vec<ColumnMapping>) -> MappingConfig {
+        MappingConfig {
+            tsv_key: default_tsv_key(),
+            json_key: default_json_key(),
+            target: default_target(),
+            null_tokens: None,
+            mode: ParseMode::default(),
+            columns,
+        }
+    }
+
+    pub fn value_parser(&self) -> TsvValueParser {
+        let mut parser = TsvValueParser { mode: self.mode, ..Default::default() };
+        if let Some(null_tokens) = &self.null_tokens {
+            parser.null_tokens = null_tokens.clone();
+        }
+        parser
+    }
+
+    pub fn from_file(path: &str) -> Result<MappingConfig> {
+        (|| -> Result<_> {
+            let config: MappingConfig = serde_json::from_str(&read_to_string(path)?)?;
+            config.check()?;
+            Ok(config)
+        })().with_context(|| anyhow!("reading mapping config {path:?}"))
+    }
+
+    fn check(&self) -> Result<()> {
+        for (i, m) in self.columns.iter().enumerate() {
+            if self.columns[..i].iter().any(|m2| m2.field == m.field) {
+                bail!("field {:?} is mapped multiple times", m.field)
+            }
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Result<MappingConfig> {
+        let config: MappingConfig = serde_json::from_str(s)?;
+        config.check()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn t_config() {
+        let config = parse(r#"{
+            "columns": [
+                {"column": "test_boolean_column", "field": "b", "type": "bool"},
+                {"column": "age", "field": "age", "type": "int"}
+            ]
+        }"#).unwrap();
+        assert_eq!(config.tsv_key, "gisaid_epi_isl");
+        assert_eq!(config.json_key.to_string(), "metadata.gisaid_epi_isl");
+        assert_eq!(config.target.to_string(), "metadata");
+        assert_eq!(config.columns[1].column_type, ColumnType::Int);
+        let parser = config.value_parser();
+        assert_eq!(parser.mode, ParseMode::Strict);
+        assert!(parser.is_null(""));
+        assert!(! parser.is_null("NA"));
+
+        let config = parse(r#"{
+            "tsv_key": "id", "json_key": "/m/id", "target": "m.x",
+            "null_tokens": ["NA"], "mode": "lenient",
+            "columns": [{"column": "d", "field": "d", "type": "date"}]
+        }"#).unwrap();
+        assert_eq!(config.tsv_key, "id");
+        let parser = config.value_parser();
+        assert_eq!(parser.mode, ParseMode::Lenient);
+        assert!(parser.is_null("na"));
+        assert!(! parser.is_null(""));
+
+        assert!(parse(r#"{"columns": [{"column": "a", "field": "a", "type": "number"}]}"#).is_err());
+        assert!(parse(r#"{"columns": [], "extra": 1}"#).is_err());
+        assert!(parse(r#"{"columns": [{"column": "a", "field": "a"}]}"#).is_err());
+        let e = parse(r#"{"columns": [
+            {"column": "a", "field": "x", "type": "int"},
+            {"column": "b", "field": "x", "type": "int"}
+        ]}"#).unwrap_err();
+        assert_eq!(e.to_string(), "field \"x\" is mapped multiple times");
+    }
+}
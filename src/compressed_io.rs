@@ -0,0 +1,221 @@
+//! Transparent (de)compression of input and output files: gzip,
+//! zstd and xz are supported. When reading, the codec is detected
+//! from the magic bytes at the start of the file; when writing, it
+//! is chosen from the file extension (`.gz`, `.zst`, `.xz`).
+
+use std::{fs::File, io::{BufRead, BufReader, BufWriter, Cursor, ErrorKind, Read, Write},
+          path::Path};
+
+use anyhow::{anyhow, Context, Result};
+use flate2::{read::MultiGzDecoder, write::GzEncoder};
+use xz2::{read::XzDecoder, write::XzEncoder};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Plain,
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+/// Enough for all magic byte sequences.
+const MAGIC_LEN: usize = 6;
+
+impl Codec {
+    pub fn from_extension<P: AsRef<Path>>(path: P) -> Codec {
+        match path.as_ref().extension().and_then(|e| e.to_str()) {
+            Some("gz") => Codec::Gzip,
+            Some("zst") => Codec::Zstd,
+            Some("xz") => Codec::Xz,
+            _ => Codec::Plain,
+        }
+    }
+
+    /// `start` is the beginning of the file, `MAGIC_LEN` bytes are
+    /// enough.
+    pub fn from_magic(start: &[u8]) -> Codec {
+        if start.starts_with(GZIP_MAGIC) {
+            Codec::Gzip
+        } else if start.starts_with(ZSTD_MAGIC) {
+            Codec::Zstd
+        } else if start.starts_with(XZ_MAGIC) {
+            Codec::Xz
+        } else {
+            Codec::Plain
+        }
+    }
+
+    /// Wrap `inp` to decompress according to this codec.
+    pub fn reader<'r, R: BufRead + Send + 'r>(self, inp: R) -> Result<Box<dyn BufRead + Send + 'r>> {
+        Ok(match self {
+            Codec::Plain => Box::new(inp),
+            Codec::Gzip => Box::new(BufReader::new(MultiGzDecoder::new(inp))),
+            Codec::Zstd => Box::new(BufReader::new(zstd::Decoder::with_buffer(inp)?)),
+            Codec::Xz => Box::new(BufReader::new(XzDecoder::new_multi_decoder(inp))),
+        })
+    }
+}
+
+
+/// Wrap `inp` to decompress it if its magic bytes indicate a
+/// supported codec. Reads until the magic bytes are complete (or
+/// EOF), as pipes can deliver fewer bytes per read.
+pub fn decoding_reader<'r, R: Read + Send + 'r>(mut inp: R) -> Result<Box<dyn BufRead + Send + 'r>> {
+    let mut start = [0; MAGIC_LEN];
+    let mut len = 0;
+    while len < MAGIC_LEN {
+        match inp.read(&mut start[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let start = &start[..len];
+    Codec::from_magic(start).reader(BufReader::new(Cursor::new(start.to_vec()).chain(inp)))
+}
+
+/// Open the file at `path` for reading, decompressing it if its
+/// magic bytes indicate a supported codec.
+pub fn open_reader<P: AsRef<Path>>(path: P) -> Result<Box<dyn BufRead + Send>> {
+    let path = path.as_ref();
+    (|| -> Result<_> {
+        decoding_reader(File::open(path)?)
+    })().with_context(|| anyhow!("opening file {path:?} for reading"))
+}
+
+/// Like `std::fs::read_to_string`, but decompressing.
+pub fn read_to_string<P: AsRef<Path>>(path: P) -> Result<String> {
+    let path = path.as_ref();
+    let mut s = String::new();
+    open_reader(path)?.read_to_string(&mut s).with_context(
+        || anyhow!("reading file {path:?}"))?;
+    Ok(s)
+}
+
+
+/// A writer compressing according to a `Codec`. `finish` must be
+/// called to complete the compressed stream.
+pub enum CodecWriter<W: Write> {
+    Plain(W),
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+    Xz(XzEncoder<W>),
+}
+
+impl<W: Write> CodecWriter<W> {
+    pub fn new(codec: Codec, outp: W) -> std::io::Result<Self> {
+        Ok(match codec {
+            Codec::Plain => CodecWriter::Plain(outp),
+            Codec::Gzip => CodecWriter::Gzip(GzEncoder::new(outp, flate2::Compression::default())),
+            Codec::Zstd => CodecWriter::Zstd(zstd::Encoder::new(outp, 0)?),
+            Codec::Xz => CodecWriter::Xz(XzEncoder::new(outp, 6)),
+        })
+    }
+
+    /// Write the end of the compressed stream, flush, and return the
+    /// underlying writer.
+    pub fn finish(self) -> std::io::Result<W> {
+        let mut outp = match self {
+            CodecWriter::Plain(w) => w,
+            CodecWriter::Gzip(w) => w.finish()?,
+            CodecWriter::Zstd(w) => w.finish()?,
+            CodecWriter::Xz(w) => w.finish()?,
+        };
+        outp.flush()?;
+        Ok(outp)
+    }
+}
+
+impl<W: Write> Write for CodecWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            CodecWriter::Plain(w) => w.write(buf),
+            CodecWriter::Gzip(w) => w.write(buf),
+            CodecWriter::Zstd(w) => w.write(buf),
+            CodecWriter::Xz(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            CodecWriter::Plain(w) => w.flush(),
+            CodecWriter::Gzip(w) => w.flush(),
+            CodecWriter::Zstd(w) => w.flush(),
+            CodecWriter::Xz(w) => w.flush(),
+        }
+    }
+}
+
+/// Create the file at `path`, compressing according to its
+/// extension.
+pub fn create_writer<P: AsRef<Path>>(path: P) -> Result<CodecWriter<BufWriter<File>>> {
+    let path = path.as_ref();
+    (|| -> Result<_> {
+        Ok(CodecWriter::new(Codec::from_extension(path),
+                            BufWriter::new(File::create(path)?))?)
+    })().with_context(|| anyhow!("creating file {path:?}"))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn t_roundtrip() {
+        let data = "{\"a\": 1}\n{\"a\": 2}\n".repeat(100);
+        for codec in [Codec::Plain, Codec::Gzip, Codec::Zstd, Codec::Xz] {
+            let mut w = CodecWriter::new(codec, Vec::new()).unwrap();
+            w.write_all(data.as_bytes()).unwrap();
+            let compressed = w.finish().unwrap();
+            assert_eq!(Codec::from_magic(&compressed), codec);
+            let mut s = String::new();
+            codec.reader(&compressed[..]).unwrap().read_to_string(&mut s).unwrap();
+            assert_eq!(s, data);
+        }
+    }
+
+    /// Delivers one byte per read, like a slow pipe.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = self.0.len().min(buf.len()).min(1);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn t_decoding_reader() {
+        let data = "{\"a\": 1}\n";
+        for codec in [Codec::Plain, Codec::Gzip, Codec::Zstd, Codec::Xz] {
+            let mut w = CodecWriter::new(codec, Vec::new()).unwrap();
+            w.write_all(data.as_bytes()).unwrap();
+            let compressed = w.finish().unwrap();
+            let mut s = String::new();
+            decoding_reader(Trickle(&compressed)).unwrap().read_to_string(&mut s).unwrap();
+            assert_eq!(s, data);
+        }
+        // Shorter than the magic bytes
+        for data in [&b""[..], b"{}", &XZ_MAGIC[..5]] {
+            let mut v = Vec::new();
+            decoding_reader(Trickle(data)).unwrap().read_to_end(&mut v).unwrap();
+            assert_eq!(v, data);
+        }
+    }
+
+    #[test]
+    fn t_from_extension() {
+        assert_eq!(Codec::from_extension("a/b.ndjson.zst"), Codec::Zstd);
+        assert_eq!(Codec::from_extension("b.tsv.gz"), Codec::Gzip);
+        assert_eq!(Codec::from_extension("b.ndjson.xz"), Codec::Xz);
+        assert_eq!(Codec::from_extension("b.ndjson"), Codec::Plain);
+    }
+}
@@ -3,36 +3,26 @@ use std::{hash::Hash, fmt::Debug, collections::HashMap};
 use anyhow::{Result, bail};
 
 
-pub fn group_by<'k, 'pk, 'v: 'pk, K: Hash + Eq, PK: Hash + Eq + Debug, V>(
+/// Group `vals` by the key returned by `getkey`, which may be a
+/// composite value like a tuple or `Vec` of references into the
+/// values; within a group, the values are indexed by their primary
+/// key, which must be unique.
+pub fn group_by<'pk, 'v: 'pk, K: Hash + Eq, PK: Hash + Eq + Debug, V>(
     vals: impl Iterator<Item = &'v V>,
-    getkey: impl Fn(&'v V) -> &'k K,
+    getkey: impl Fn(&'v V) -> K,
     get_primary_key: impl Fn(&'v V) -> &'pk PK,
-) -> Result<HashMap<&'k K, HashMap<&'pk PK, &'v V>>>
+) -> Result<HashMap<K, HashMap<&'pk PK, &'v V>>>
 {
-    let mut m: HashMap<&'k K, HashMap<&'pk PK, &'v V>> = HashMap::new();
+    let mut m: HashMap<K, HashMap<&'pk PK, &'v V>> = HashMap::new();
     for val in vals {
         let key = getkey(val);
         let pkey = get_primary_key(val);
-        if let Some(v) = m.get_mut(key) {
-            if v.contains_key(pkey) {
-                bail!("duplicate pkey {pkey:?}");
-            }
-            v.insert(pkey, val);
-        } else {
-            let mut g = HashMap::new();
-            g.insert(pkey, val);
-            m.insert(key, g);
+        let g = m.entry(key).or_default();
+        if g.contains_key(pkey) {
+            bail!("duplicate pkey {pkey:?}");
         }
+        g.insert(pkey, val);
     }
     Ok(m)
 }
 
-
-pub fn print_group_sizes<'k, 'pk, 'v: 'pk, K: Hash + Eq + Debug, PK: Hash + Eq + Debug, V>(
-    m: &HashMap<K, HashMap<PK, V>>
-) {
-    for (key, val) in m.iter() {
-        println!("{key:?} => {}", val.len());
-    }
-}
-
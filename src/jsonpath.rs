@@ -0,0 +1,147 @@
+//! Paths into nested JSON objects, written either dotted
+//! (`metadata.qc`) or as JSON Pointer (`/metadata/qc`, RFC 6901).
+
+use std::{convert::TryFrom, fmt::Display};
+
+use anyhow::{anyhow, bail, Result};
+use jzon::{JsonValue, object::Object};
+use kstring::KString;
+
+use crate::easyjson::EasyJsonValue;
+
+
+/// Only object member names are supported as path segments, array
+/// indices are not. The empty string is the path to the root value.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct JsonPath(Vec<KString>);
+
+impl JsonPath {
+    pub fn parse(s: &str) -> Result<JsonPath> {
+        if s.is_empty() {
+            return Ok(JsonPath(Vec::new()))
+        }
+        if let Some(pointer) = s.strip_prefix('/') {
+            Ok(JsonPath(pointer.split('/').map(
+                |segment| {
+                    if segment.contains('~') {
+                        let unescaped = segment.replace("~1", "/").replace("~0", "~");
+                        if segment.replace("~1", "").replace("~0", "").contains('~') {
+                            bail!("invalid escape in JSON Pointer {s:?}")
+                        }
+                        Ok(KString::from_string(unescaped))
+                    } else {
+                        Ok(KString::from_ref(segment))
+                    }
+                }).collect::<Result<_>>()?))
+        } else {
+            Ok(JsonPath(s.split('.').map(
+                |segment| {
+                    if segment.is_empty() {
+                        bail!("empty segment in path {s:?}")
+                    }
+                    Ok(KString::from_ref(segment))
+                }).collect::<Result<_>>()?))
+        }
+    }
+
+    pub fn segments(&self) -> &[KString] {
+        &self.0
+    }
+
+    /// The path with `segment` added at the end.
+    pub fn join(&self, segment: &str) -> JsonPath {
+        let mut p = self.0.clone();
+        p.push(KString::from_ref(segment));
+        JsonPath(p)
+    }
+
+    pub fn get<'v>(&self, value: &'v JsonValue) -> Result<&'v JsonValue> {
+        let mut v = value;
+        for (i, segment) in self.0.iter().enumerate() {
+            v = v.object()?.get(segment).ok_or_else(
+                || anyhow!("missing key {:?} at {}", segment.as_str(),
+                           JsonPath(self.0[..i].to_vec())))?;
+        }
+        Ok(v)
+    }
+
+    /// Get the object at this path, creating missing objects on the
+    /// way.
+    pub fn get_or_create_object_mut<'v>(&self, value: &'v mut JsonValue) -> Result<&'v mut Object> {
+        let mut v = value;
+        for segment in &self.0 {
+            let o = v.object_mut()?;
+            if o.get(segment).is_none() {
+                o.insert(segment, JsonValue::new_object());
+            }
+            v = o.get_mut(segment).expect("just checked or inserted");
+        }
+        v.object_mut()
+    }
+}
+
+impl TryFrom<String> for JsonPath {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        JsonPath::parse(&value)
+    }
+}
+
+/// Shows the dotted form (or JSON Pointer form if a segment contains a
+/// dot).
+impl Display for JsonPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0.iter().any(|s| s.is_empty() || s.contains('.')) {
+            for segment in &self.0 {
+                write!(f, "/{}", segment.replace('~', "~0").replace('/', "~1"))?;
+            }
+            Ok(())
+        } else {
+            write!(f, "{}", self.0.iter().map(|s| s.as_str()).collect::<Vec<_>>().join("."))
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segments(s: &str) -> Vec<String> {
+        JsonPath::parse(s).unwrap().0.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn t_parse() {
+        assert_eq!(segments(""), Vec::<String>::new());
+        assert_eq!(segments("metadata"), vec!["metadata"]);
+        assert_eq!(segments("metadata.qc"), vec!["metadata", "qc"]);
+        assert_eq!(segments("/metadata/qc"), vec!["metadata", "qc"]);
+        assert_eq!(segments("/a~1b/c~0d/e.f"), vec!["a/b", "c~d", "e.f"]);
+        assert!(JsonPath::parse("metadata..qc").is_err());
+        assert!(JsonPath::parse("/a~2").is_err());
+    }
+
+    #[test]
+    fn t_display() {
+        for s in ["", "metadata.qc", "/e.f/g~1h"] {
+            assert_eq!(JsonPath::parse(s).unwrap().to_string(), s);
+        }
+    }
+
+    #[test]
+    fn t_get() {
+        let mut v = jzon::parse(r#"{"metadata": {"id": "E1", "n": 3}}"#).unwrap();
+        let p = JsonPath::parse("metadata.id").unwrap();
+        assert_eq!(p.get(&v).unwrap().as_str(), Some("E1"));
+        assert!(JsonPath::parse("metadata.qc").unwrap().get(&v).is_err());
+        assert!(JsonPath::parse("metadata.n.x").unwrap().get(&v).is_err());
+
+        let o = JsonPath::parse("metadata.qc.x").unwrap()
+            .get_or_create_object_mut(&mut v).unwrap();
+        o.insert("score", 1.into());
+        assert_eq!(v.dump(), r#"{"metadata":{"id":"E1","n":3,"qc":{"x":{"score":1}}}}"#);
+    }
+}
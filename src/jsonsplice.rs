@@ -0,0 +1,451 @@
+//! Editing JSON text without reparsing and reserializing all of it:
+//! values are located by scanning the raw bytes, and only the edited
+//! spans are replaced, the rest of the text is kept byte for byte.
+//!
+//! The scanner only checks the structure it walks through (brackets,
+//! string quoting); use a real parser where validation matters.
+
+use std::{collections::BTreeMap, ops::Range};
+
+use anyhow::{anyhow, bail, Result};
+use jzon::JsonValue;
+use kstring::KString;
+
+use crate::jsonpath::JsonPath;
+
+
+fn skip_ws(s: &[u8], mut pos: usize) -> usize {
+    while pos < s.len() && matches!(s[pos], b' ' | b'\t' | b'\n' | b'\r') {
+        pos += 1;
+    }
+    pos
+}
+
+fn expect(s: &[u8], pos: usize, c: u8) -> Result<()> {
+    match s.get(pos) {
+        Some(c2) if *c2 == c => Ok(()),
+        Some(c2) => bail!("expecting {:?} at byte {pos}, got {:?}", c as char, *c2 as char),
+        None => bail!("expecting {:?} at byte {pos}, got end of input", c as char),
+    }
+}
+
+/// `pos` is at the opening quote; returns the position after the
+/// closing quote.
+fn string_end(s: &[u8], pos: usize) -> Result<usize> {
+    expect(s, pos, b'"')?;
+    let mut i = pos + 1;
+    while i < s.len() {
+        match s[i] {
+            b'"' => return Ok(i + 1),
+            b'\\' => i += 2,
+            _ => i += 1,
+        }
+    }
+    bail!("unterminated string starting at byte {pos}")
+}
+
+/// `pos` is at the start of a value; returns the position after it.
+fn value_end(s: &[u8], pos: usize) -> Result<usize> {
+    match s.get(pos) {
+        Some(b'"') => string_end(s, pos),
+        Some(b'{') => Ok(RawObject::scan(s, pos)?.end),
+        Some(b'[') => {
+            let mut i = skip_ws(s, pos + 1);
+            if s.get(i) == Some(&b']') {
+                return Ok(i + 1)
+            }
+            loop {
+                i = skip_ws(s, value_end(s, i)?);
+                match s.get(i) {
+                    Some(b',') => i = skip_ws(s, i + 1),
+                    Some(b']') => return Ok(i + 1),
+                    _ => bail!("expecting ',' or ']' at byte {i}")
+                }
+            }
+        }
+        Some(_) => {
+            // number, true, false, null
+            let mut i = pos;
+            while i < s.len() && matches!(s[i], b'0'..=b'9' | b'a'..=b'z' | b'-' | b'+' | b'.' | b'E') {
+                i += 1;
+            }
+            if i == pos {
+                bail!("invalid value at byte {pos}")
+            }
+            Ok(i)
+        }
+        None => bail!("expecting value at byte {pos}, got end of input")
+    }
+}
+
+/// The position of the root value in `s`, checking that nothing but
+/// whitespace follows it, and the scanned root object if it is one.
+fn root_value(s: &[u8]) -> Result<(Range<usize>, Option<RawObject>)> {
+    let start = skip_ws(s, 0);
+    let (end, object) = if s.get(start) == Some(&b'{') {
+        let object = RawObject::scan(s, start)?;
+        (object.end, Some(object))
+    } else {
+        (value_end(s, start)?, None)
+    };
+    if skip_ws(s, end) != s.len() {
+        bail!("garbage after JSON value at byte {end}")
+    }
+    Ok((start..end, object))
+}
+
+
+struct RawMember {
+    /// Including the quotes
+    key: Range<usize>,
+    value: Range<usize>,
+    /// If the value is an object
+    object: Option<RawObject>,
+}
+
+struct RawObject {
+    members: Vec<RawMember>,
+    /// Position of the closing brace
+    close: usize,
+    end: usize,
+}
+
+impl RawObject {
+    /// Scan the object at `pos`, including the objects nested in it
+    /// (but not those in arrays).
+    fn scan(s: &[u8], pos: usize) -> Result<RawObject> {
+        expect(s, pos, b'{')?;
+        let mut members = Vec::new();
+        let mut i = skip_ws(s, pos + 1);
+        if s.get(i) != Some(&b'}') {
+            loop {
+                let key = i..string_end(s, i)?;
+                i = skip_ws(s, key.end);
+                expect(s, i, b':')?;
+                i = skip_ws(s, i + 1);
+                let (value, object) = if s.get(i) == Some(&b'{') {
+                    let object = RawObject::scan(s, i)?;
+                    (i..object.end, Some(object))
+                } else {
+                    (i..value_end(s, i)?, None)
+                };
+                i = skip_ws(s, value.end);
+                members.push(RawMember { key, value, object });
+                match s.get(i) {
+                    Some(b',') => i = skip_ws(s, i + 1),
+                    Some(b'}') => break,
+                    _ => bail!("expecting ',' or '}}' at byte {i}")
+                }
+            }
+        }
+        Ok(RawObject { members, close: i, end: i + 1 })
+    }
+
+    /// The last member with the given key (as most parsers keep the
+    /// last of duplicate keys).
+    fn get(&self, s: &str, key: &str) -> Result<Option<&RawMember>> {
+        for m in self.members.iter().rev() {
+            let raw = &s[m.key.clone()];
+            let matches = if raw.contains('\\') {
+                jzon::parse(raw)?.as_str() == Some(key)
+            } else {
+                &raw[1..raw.len() - 1] == key
+            };
+            if matches {
+                return Ok(Some(m))
+            }
+        }
+        Ok(None)
+    }
+}
+
+
+/// JSON text scanned once, for looking up values at paths and
+/// collecting edits (see `set_fields`) without rescanning.
+pub struct RawJson<'s> {
+    s: &'s str,
+    root: Range<usize>,
+    /// If the root value is an object
+    object: Option<RawObject>,
+}
+
+impl<'s> RawJson<'s> {
+    pub fn scan(s: &'s str) -> Result<Self> {
+        let (root, object) = root_value(s.as_bytes())?;
+        Ok(RawJson { s, root, object })
+    }
+
+    /// The raw text of the value at `path`, or None if a key along
+    /// the path is missing.
+    pub fn get_raw(&self, path: &JsonPath) -> Result<Option<&'s str>> {
+        let mut value = &self.root;
+        let mut object = self.object.as_ref();
+        for segment in path.segments() {
+            let obj = object.ok_or_else(|| anyhow!(
+                "expecting object at byte {}, with key {:?}", value.start, segment.as_str()))?;
+            match obj.get(self.s, segment)? {
+                Some(m) => {
+                    value = &m.value;
+                    object = m.object.as_ref();
+                }
+                None => return Ok(None)
+            }
+        }
+        Ok(Some(&self.s[value.clone()]))
+    }
+
+    /// Like `get_raw`, but parsed.
+    pub fn get_value(&self, path: &JsonPath) -> Result<Option<JsonValue>> {
+        match self.get_raw(path)? {
+            Some(raw) => Ok(Some(jzon::parse(raw).map_err(
+                |e| anyhow!("invalid JSON at {path}: {e}"))?)),
+            None => Ok(None)
+        }
+    }
+
+    /// Add the edits to set `fields` in the object at `target` to
+    /// `edits`, creating the object (and its missing parents) if
+    /// necessary. Existing field values are replaced in place, new
+    /// fields appended at the end of the object, following the
+    /// separator style of the existing members.
+    pub fn set_fields(
+        &self, target: &JsonPath, fields: &[(&str, JsonValue)], edits: &mut Edits
+    ) -> Result<()> {
+        let s = self.s;
+        let mut value = &self.root;
+        let mut object = self.object.as_ref();
+        let mut segments = target.segments().iter();
+        loop {
+            let obj = object.ok_or_else(
+                || anyhow!("expecting object at byte {} for path {target}", value.start))?;
+            let colon = match &obj.members[..] {
+                [] => ":",
+                [m, ..] => &s[m.key.end..m.value.start],
+            };
+            let mut inserts = Vec::new();
+            match segments.next() {
+                Some(segment) => {
+                    if let Some(m) = obj.get(s, segment)? {
+                        value = &m.value;
+                        object = m.object.as_ref();
+                        continue
+                    }
+                    // Build the missing part of the path as a value
+                    let mut o = JsonValue::new_object();
+                    for (k, v) in fields {
+                        o.insert(k, v.clone()).expect("object");
+                    }
+                    for segment in segments.as_slice().iter().rev() {
+                        let mut o2 = JsonValue::new_object();
+                        o2.insert(segment, o).expect("object");
+                        o = o2;
+                    }
+                    inserts.push((segment.as_str(), o));
+                }
+                None => {
+                    for (k, v) in fields {
+                        if let Some(m) = obj.get(s, k)? {
+                            edits.replacements.push((m.value.clone(), v.dump()));
+                        } else {
+                            inserts.push((k, v.clone()));
+                        }
+                    }
+                }
+            }
+            if ! inserts.is_empty() {
+                let pos = obj.members.last().map_or(obj.close, |m| m.value.end);
+                let comma = match &obj.members[..] {
+                    [m0, m1, ..] => &s[m0.value.end..m1.key.start],
+                    _ => ",",
+                };
+                let insert = edits.inserts.entry(pos).or_insert_with(|| Insert {
+                    colon: colon.into(),
+                    comma: comma.into(),
+                    after_member: ! obj.members.is_empty(),
+                    members: Vec::new(),
+                });
+                for (key, value) in inserts {
+                    insert.add(key, value)?;
+                }
+            }
+            return Ok(())
+        }
+    }
+
+    /// The text with `edits` applied.
+    pub fn apply(&self, edits: Edits) -> Result<String> {
+        let s = self.s;
+        let mut spans: Vec<(Range<usize>, String)> = edits.replacements;
+        for (pos, insert) in edits.inserts {
+            spans.push((pos..pos, insert.to_string()));
+        }
+        spans.sort_by_key(|(span, _)| (span.start, span.end));
+        let mut out = String::with_capacity(s.len() + 64);
+        let mut pos = 0;
+        for (span, replacement) in spans {
+            if span.start < pos {
+                bail!("overlapping edits at byte {}", span.start)
+            }
+            out.push_str(&s[pos..span.start]);
+            out.push_str(&replacement);
+            pos = span.end;
+        }
+        out.push_str(&s[pos..]);
+        Ok(out)
+    }
+}
+
+/// Members to insert at one position of an object.
+struct Insert {
+    colon: String,
+    comma: String,
+    /// Whether there are members before the position
+    after_member: bool,
+    members: Vec<(KString, JsonValue)>,
+}
+
+impl Insert {
+    /// Add a member; objects for the same key (from creating the
+    /// same missing parent for several targets) are merged.
+    fn add(&mut self, key: &str, value: JsonValue) -> Result<()> {
+        match self.members.iter_mut().find(|(k, _)| k == key) {
+            None => self.members.push((KString::from_ref(key), value)),
+            Some((_, old)) => merge_objects(old, value).map_err(
+                |e| anyhow!("conflicting edits for key {key:?}: {e}"))?,
+        }
+        Ok(())
+    }
+}
+
+fn merge_objects(a: &mut JsonValue, b: JsonValue) -> Result<()> {
+    match (a, b) {
+        (JsonValue::Object(a), JsonValue::Object(b)) => {
+            for (k, v) in b.iter() {
+                match a.get_mut(k) {
+                    Some(old) => merge_objects(old, v.clone())?,
+                    None => a.insert(k, v.clone()),
+                }
+            }
+            Ok(())
+        }
+        (a, b) => if *a == b {
+            Ok(())
+        } else {
+            bail!("{} vs. {}", a.dump(), b.dump())
+        }
+    }
+}
+
+impl std::fmt::Display for Insert {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, (key, value)) in self.members.iter().enumerate() {
+            if i > 0 || self.after_member {
+                f.write_str(&self.comma)?;
+            }
+            write!(f, "{}{}{}", JsonValue::from(key.as_str()).dump(), self.colon, value.dump())?;
+        }
+        Ok(())
+    }
+}
+
+/// Pending changes to a `RawJson` text.
+#[derive(Default)]
+pub struct Edits {
+    /// (span of a value, replacement)
+    replacements: Vec<(Range<usize>, String)>,
+    /// By position
+    inserts: BTreeMap<usize, Insert>,
+}
+
+
+/// The raw text of the value at `path` in the JSON text `s`, or None
+/// if a key along the path is missing. Use `RawJson` for several
+/// lookups in the same text.
+pub fn get_raw<'s>(s: &'s str, path: &JsonPath) -> Result<Option<&'s str>> {
+    RawJson::scan(s)?.get_raw(path)
+}
+
+/// Like `get_raw`, but parsed.
+pub fn get_value(s: &str, path: &JsonPath) -> Result<Option<JsonValue>> {
+    RawJson::scan(s)?.get_value(path)
+}
+
+/// Set `fields` in the object at `target` in the JSON text `s` and
+/// return the resulting text, see `RawJson::set_fields`.
+pub fn set_fields(s: &str, target: &JsonPath, fields: &[(&str, JsonValue)]) -> Result<String> {
+    let raw = RawJson::scan(s)?;
+    let mut edits = Edits::default();
+    raw.set_fields(target, fields, &mut edits)?;
+    raw.apply(edits)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(s: &str) -> JsonPath {
+        JsonPath::parse(s).unwrap()
+    }
+
+    #[test]
+    fn t_get_raw() {
+        let s = r#" {"a": [1, {"b": "}"}], "m": {"k1": 1.50e3, "id": "E\"1"}} "#;
+        assert_eq!(get_raw(s, &path("m.id")).unwrap(), Some(r#""E\"1""#));
+        assert_eq!(get_raw(s, &path("m.k1")).unwrap(), Some("1.50e3"));
+        assert_eq!(get_raw(s, &path("a")).unwrap(), Some(r#"[1, {"b": "}"}]"#));
+        assert_eq!(get_raw(s, &path("m.x")).unwrap(), None);
+        assert!(get_raw(s, &path("a.b")).is_err());
+        assert!(get_raw(r#"{"a": 1} x"#, &path("a")).is_err());
+        assert!(get_raw(r#"{"a": [1, 2}"#, &path("a")).is_err());
+    }
+
+    #[test]
+    fn t_set_fields() {
+        let s = r#"{"metadata": {"id": "E1", "x": 1.50, "b": null}, "seq": "AC"}"#;
+        let fields = [("b", true.into()), ("n", 3.into())];
+        assert_eq!(
+            set_fields(s, &path("metadata"), &fields).unwrap(),
+            r#"{"metadata": {"id": "E1", "x": 1.50, "b": true, "n": 3}, "seq": "AC"}"#);
+        assert_eq!(
+            set_fields(s, &path("metadata.qc.r"), &fields).unwrap(),
+            r#"{"metadata": {"id": "E1", "x": 1.50, "b": null, "qc": {"r":{"b":true,"n":3}}}, "seq": "AC"}"#);
+        assert_eq!(
+            set_fields(r#"{"m":{}}"#, &path("m"), &fields).unwrap(),
+            r#"{"m":{"b":true,"n":3}}"#);
+        assert_eq!(
+            set_fields(r#"{"m":{"a" :1}}"#, &path("m"), &fields).unwrap(),
+            r#"{"m":{"a" :1,"b" :true,"n" :3}}"#);
+        assert_eq!(
+            set_fields(r#"{"n":0,"m":1,"b":false}"#, &path(""), &fields).unwrap(),
+            r#"{"n":3,"m":1,"b":true}"#);
+        assert!(set_fields(r#"{"m":1}"#, &path("m"), &fields).is_err());
+    }
+
+    #[test]
+    fn t_edits() {
+        let edit = |s: &str, targets: &[(&str, &str, JsonValue)]| -> Result<String> {
+            let raw = RawJson::scan(s)?;
+            let mut edits = Edits::default();
+            for (target, field, value) in targets {
+                raw.set_fields(&path(target), &[(field, value.clone())], &mut edits)?;
+            }
+            raw.apply(edits)
+        };
+        let s = r#"{"m": {"id": "E1", "x": 1}, "n": {}}"#;
+        let raw = RawJson::scan(s).unwrap();
+        assert_eq!(raw.get_raw(&path("m.x")).unwrap(), Some("1"));
+        assert_eq!(raw.get_value(&path("m")).unwrap().unwrap().dump(), r#"{"id":"E1","x":1}"#);
+        assert!(raw.get_raw(&path("m.id.y")).is_err());
+
+        assert_eq!(edit(s, &[("m", "x", 2.into()), ("n", "a", 1.into()), ("n", "b", 2.into()),
+                             ("", "c", 3.into())]).unwrap(),
+                   r#"{"m": {"id": "E1", "x": 2}, "n": {"a":1,"b":2}, "c": 3}"#);
+        // Missing parents created once for several targets
+        assert_eq!(edit(s, &[("q.a", "x", 1.into()), ("q.b", "y", 2.into()), ("q.a", "z", 3.into())])
+                   .unwrap(),
+                   r#"{"m": {"id": "E1", "x": 1}, "n": {}, "q": {"a":{"x":1,"z":3},"b":{"y":2}}}"#);
+        assert!(edit(s, &[("q", "x", 1.into()), ("q", "x", 2.into())]).is_err());
+        assert!(edit(s, &[("", "m", 1.into()), ("m", "x", 2.into())]).is_err());
+    }
+}
@@ -1,8 +1,21 @@
 
 pub mod easyjson;
 pub mod tempfile;
+pub mod compressed_io;
 pub mod io_read_to_string;
 pub mod groupby;
 pub mod pangolineage;
 pub mod lineagelist;
 pub mod lineagelist_index;
+pub mod lineage_tree;
+pub mod lineage_interner;
+pub mod tsv;
+pub mod tsv_value;
+pub mod jsonpath;
+pub mod jsonsplice;
+pub mod column_mapping;
+pub mod ndjson_update;
+pub mod parallel_lines;
+pub mod query;
+pub mod query_engine;
+pub mod test_cases;
@@ -0,0 +1,241 @@
+//! Compact handling of the lineages of many records: every distinct
+//! lineage string is parsed and canonicalized once, and mapped to a
+//! `LineageId`. After numbering the tree of the interned lineages,
+//! ancestry checks between IDs take constant time.
+
+use std::{collections::HashMap, convert::{TryFrom, TryInto}};
+
+use kstring::KString;
+
+use crate::{lineagelist_index::LineageAliases,
+            pangolineage::{PangoLineage, HaplotypeBasename, LineageName,
+                           LineageParseError, LineageQuery, LineagePattern}};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct LineageId(u32);
+
+impl LineageId {
+    fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+#[derive(Debug)]
+struct Node {
+    canonical: PangoLineage<HaplotypeBasename>,
+    children: Vec<LineageId>,
+    is_root: bool,
+}
+
+#[derive(Debug)]
+pub struct LineageInterner<'a> {
+    aliases: &'a LineageAliases,
+    by_string: HashMap<KString, LineageId>,
+    by_canonical: HashMap<PangoLineage<HaplotypeBasename>, LineageId>,
+    /// Indexed by `LineageId`; includes the ancestors of all interned
+    /// lineages
+    nodes: Vec<Node>,
+    /// (pre-order number, largest pre-order number in the subtree),
+    /// indexed by `LineageId`; only covers the nodes that existed when
+    /// `number` was last called
+    intervals: Vec<(u32, u32)>,
+}
+
+impl<'a> LineageInterner<'a> {
+    pub fn new(aliases: &'a LineageAliases) -> Self {
+        LineageInterner {
+            aliases,
+            by_string: HashMap::new(),
+            by_canonical: HashMap::new(),
+            nodes: Vec::new(),
+            intervals: Vec::new(),
+        }
+    }
+
+    /// Parse and canonicalize `name` (see
+    /// `LineageAliases::canonicalize_name`) if it wasn't seen before.
+    /// Names with the same canonical form get the same ID.
+    pub fn intern(&mut self, name: &str) -> Result<LineageId, LineageParseError> {
+        if let Some(id) = self.by_string.get(name) {
+            return Ok(*id)
+        }
+        let canonical = self.aliases.canonicalize_name(LineageName::try_from(name)?);
+        let id = self.intern_canonical(canonical);
+        self.by_string.insert(KString::from_ref(name), id);
+        Ok(id)
+    }
+
+    fn intern_canonical(&mut self, canonical: PangoLineage<HaplotypeBasename>) -> LineageId {
+        if let Some(id) = self.by_canonical.get(&canonical) {
+            return *id
+        }
+        let parent = canonical.parent().map(|p| self.intern_canonical(p));
+        let id = LineageId(self.nodes.len().try_into().expect("less than 2^32 lineages"));
+        if let Some(parent) = parent {
+            self.nodes[parent.index()].children.push(id);
+        }
+        self.by_canonical.insert(canonical.clone(), id);
+        self.nodes.push(Node { canonical, children: Vec::new(), is_root: parent.is_none() });
+        id
+    }
+
+    /// The ID of a name that was interned before.
+    pub fn get(&self, name: &str) -> Option<LineageId> {
+        self.by_string.get(name).copied()
+    }
+
+    /// The ID of a canonical lineage, if it or a sublineage of it was
+    /// interned.
+    pub fn get_canonical(&self, canonical: &PangoLineage<HaplotypeBasename>) -> Option<LineageId> {
+        self.by_canonical.get(canonical).copied()
+    }
+
+    pub fn canonical(&self, id: LineageId) -> &PangoLineage<HaplotypeBasename> {
+        &self.nodes[id.index()].canonical
+    }
+
+    /// The number of IDs handed out, including those for ancestors.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Number the tree of the interned lineages, to make
+    /// `is_ancestor_of` constant time; call again after interning
+    /// further lineages (it stays correct, but gets slower for the
+    /// new ones otherwise).
+    pub fn number(&mut self) {
+        let mut intervals = vec![(0, 0); self.nodes.len()];
+        let mut counter = 0;
+        // (node, whether its children were visited)
+        let mut stack: Vec<(LineageId, bool)> = (0..self.nodes.len())
+            .filter(|i| self.nodes[*i].is_root)
+            .map(|i| (LineageId(i as u32), false))
+            .collect();
+        while let Some((id, visited)) = stack.pop() {
+            if visited {
+                intervals[id.index()].1 = counter - 1;
+            } else {
+                intervals[id.index()].0 = counter;
+                counter += 1;
+                stack.push((id, true));
+                stack.extend(self.nodes[id.index()].children.iter().map(|c| (*c, false)));
+            }
+        }
+        self.intervals = intervals;
+    }
+
+    /// Whether `a` is an ancestor of `b` (or the same, with
+    /// `include_self`).
+    pub fn is_ancestor_of(&self, a: LineageId, b: LineageId, include_self: bool) -> bool {
+        if a == b {
+            return include_self
+        }
+        match (self.intervals.get(a.index()), self.intervals.get(b.index())) {
+            (Some((a_pre, a_last)), Some((b_pre, _))) => a_pre <= b_pre && b_pre <= a_last,
+            _ => self.canonical(a).is_ancestor_of(self.canonical(b), false)
+        }
+    }
+
+    /// Resolve the patterns in `query` to IDs.
+    pub fn compile_query(&self, query: &LineageQuery) -> InternedLineageQuery {
+        let compile = |patterns: &[LineagePattern]| patterns.iter().filter_map(|p| {
+            let (l, with_sublineages) = match p {
+                LineagePattern::Exact(l) => (l, false),
+                LineagePattern::WithSublineages(l) => (l, true),
+            };
+            // If the lineage wasn't interned, none of its sublineages
+            // were either, thus it can't match
+            self.get_canonical(&self.aliases.canonicalize(l.clone()))
+                .map(|id| (id, with_sublineages))
+        }).collect();
+        InternedLineageQuery {
+            include: compile(&query.include),
+            exclude: compile(&query.exclude),
+        }
+    }
+}
+
+/// A `LineageQuery` for matching `LineageId`s, see
+/// `LineageInterner::compile_query`.
+#[derive(Debug, Clone)]
+pub struct InternedLineageQuery {
+    /// (lineage, with sublineages)
+    include: Vec<(LineageId, bool)>,
+    exclude: Vec<(LineageId, bool)>,
+}
+
+impl InternedLineageQuery {
+    pub fn matches(&self, interner: &LineageInterner, id: LineageId) -> bool {
+        let matches_any = |patterns: &[(LineageId, bool)]| {
+            patterns.iter().any(|(p, with_sublineages)| if *with_sublineages {
+                interner.is_ancestor_of(*p, id, true)
+            } else {
+                *p == id
+            })
+        };
+        matches_any(&self.include) && ! matches_any(&self.exclude)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn t_interner() {
+        let aliases = LineageAliases::from_alias_key_json(&jzon::parse(r#"{
+            "A": "", "B": "", "BA": "B.1.1.529", "Q": "B.1.1.7"
+        }"#).unwrap()).unwrap();
+        let mut interner = LineageInterner::new(&aliases);
+        let names = ["BA.2", "B.1.1.529.2", "BA.2.75", "BA.1", "Q.1", "A.1", "XBB.1.5", "B.1.1.7"];
+        let ids: Vec<_> = names.iter().map(|n| interner.intern(n).unwrap()).collect();
+        assert_eq!(ids[0], ids[1]);
+        assert_eq!(interner.intern("BA.2").unwrap(), ids[0]);
+        assert_eq!(interner.get("B.1.1.529.2"), Some(ids[0]));
+        assert_eq!(interner.get("BA.3"), None);
+        assert_eq!(interner.canonical(ids[2]).to_string(), "B.1.1.529.2.75");
+        assert!(interner.intern("B..1").is_err());
+
+        // Without and with numbering
+        for step in 0..2 {
+            if step == 1 {
+                interner.number();
+            }
+            let ids: Vec<_> = names.iter().chain(&["BA.2.75.1", "B"])
+                .map(|n| interner.intern(n).unwrap()).collect();
+            let anc = |a: usize, b: usize| interner.is_ancestor_of(ids[a], ids[b], false);
+            assert!(anc(0, 2));
+            assert!(anc(0, 8));
+            assert!(anc(2, 8));
+            assert!(anc(7, 4));
+            assert!(anc(9, 7));
+            assert!(anc(9, 8));
+            assert!(! anc(0, 1));
+            assert!(interner.is_ancestor_of(ids[0], ids[1], true));
+            assert!(! anc(2, 0));
+            assert!(! anc(3, 2));
+            assert!(! anc(4, 7));
+            assert!(! anc(9, 5));
+            assert!(! anc(0, 6));
+            assert!(! anc(9, 6));
+        }
+        // Interned after numbering
+        let new = interner.intern("BA.2.75.1.1").unwrap();
+        assert!(interner.intervals.len() < interner.len());
+        assert!(interner.is_ancestor_of(ids[2], new, false));
+        assert!(! interner.is_ancestor_of(new, ids[2], false));
+        assert!(! interner.is_ancestor_of(ids[3], new, false));
+
+        let query = LineageQuery::try_from("B.1.1* except BA.2.75*, BA.9*").unwrap();
+        let query = interner.compile_query(&query);
+        let matches: Vec<_> = names.iter().map(
+            |n| query.matches(&interner, interner.get(n).unwrap())).collect();
+        assert_eq!(matches, [true, true, false, true, true, false, false, true]);
+    }
+}
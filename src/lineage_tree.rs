@@ -0,0 +1,282 @@
+//! The tree of all known pango lineages, in canonical form (see
+//! `LineageAliases::canonicalize`), for queries that go beyond the
+//! pairwise `PangoLineage::is_ancestor_of`.
+//!
+//! Lineages that are implied by the path of a listed lineage but not
+//! listed themselves (e.g. `B.1.1.529.2.75.3` for `BM.1`) are added
+//! to keep the tree connected. Recombinants (and original haplotypes)
+//! are roots; see `LineageAliases::descends_from` for ancestry via
+//! recombination.
+
+use std::{collections::HashMap, convert::TryFrom};
+
+use anyhow::{anyhow, Context, Result};
+use jzon::JsonValue;
+
+use crate::{easyjson::EasyJsonValue,
+            lineagelist_index::LineageAliases,
+            pangolineage::{PangoLineage, HaplotypeBasename, BaseName, LineageName}};
+
+
+#[derive(Debug)]
+struct Node {
+    lineage: PangoLineage<HaplotypeBasename>,
+    parent: Option<usize>,
+    /// Sorted by path
+    children: Vec<usize>,
+    depth: usize,
+}
+
+#[derive(Debug)]
+pub struct LineageTree {
+    nodes: Vec<Node>,
+    /// Sorted by name
+    roots: Vec<usize>,
+    /// Canonical lineage -> index in `nodes`
+    index: HashMap<PangoLineage<HaplotypeBasename>, usize>,
+}
+
+impl LineageTree {
+    /// Build the tree from lineage names (with or without aliases);
+    /// withdrawn names (`*J.1`) are ignored, invalid ones skipped with
+    /// a warning, like in `LineageAliases::from_json`.
+    pub fn new<'s>(
+        aliases: &LineageAliases, names: impl IntoIterator<Item = &'s str>
+    ) -> Result<Self> {
+        let mut tree = LineageTree { nodes: Vec::new(), roots: Vec::new(), index: HashMap::new() };
+        for name in names {
+            let name = match LineageName::try_from(name) {
+                Ok(name) => name,
+                Err(e) => {
+                    eprintln!("ignoring lineage {name:?}: {e}");
+                    continue
+                }
+            };
+            if ! name.withdrawn {
+                tree.insert(aliases.canonicalize(name.lineage));
+            }
+        }
+        let last_elements: Vec<_> = tree.nodes.iter().map(
+            |n| n.lineage.1.as_ref().last().copied()).collect();
+        for node in &mut tree.nodes {
+            node.children.sort_by_key(|c| last_elements[*c]);
+        }
+        let nodes = &tree.nodes;
+        tree.roots.sort_by_key(|r| nodes[*r].lineage.0.as_str());
+        Ok(tree)
+    }
+
+    /// Build the tree from all lineages in the parsed contents of a
+    /// `lineage_data.json` file (see `LineageAliases::from_json`).
+    pub fn from_lineage_data(aliases: &LineageAliases, data: &JsonValue) -> Result<Self> {
+        (|| -> Result<_> {
+            Self::new(aliases, data.object()?.iter().map(|(name, _)| name))
+        })().with_context(|| anyhow!("building lineage tree"))
+    }
+
+    /// Add `lineage` and its missing ancestors, returns its index.
+    fn insert(&mut self, lineage: PangoLineage<HaplotypeBasename>) -> usize {
+        if let Some(i) = self.index.get(&lineage) {
+            return *i
+        }
+        let parent = lineage.parent().map(|p| self.insert(p));
+        let i = self.nodes.len();
+        let depth = match parent {
+            Some(p) => {
+                self.nodes[p].children.push(i);
+                self.nodes[p].depth + 1
+            }
+            None => {
+                self.roots.push(i);
+                0
+            }
+        };
+        self.index.insert(lineage.clone(), i);
+        self.nodes.push(Node { lineage, parent, children: Vec::new(), depth });
+        i
+    }
+
+    fn id(&self, lineage: &PangoLineage<HaplotypeBasename>) -> Option<usize> {
+        self.index.get(lineage).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn contains(&self, lineage: &PangoLineage<HaplotypeBasename>) -> bool {
+        self.id(lineage).is_some()
+    }
+
+    /// The lineages without parent.
+    pub fn roots(&self) -> impl Iterator<Item = &PangoLineage<HaplotypeBasename>> {
+        self.roots.iter().map(move |i| &self.nodes[*i].lineage)
+    }
+
+    /// None if `lineage` is a root or not in the tree.
+    pub fn parent(
+        &self, lineage: &PangoLineage<HaplotypeBasename>
+    ) -> Option<&PangoLineage<HaplotypeBasename>> {
+        let p = self.nodes[self.id(lineage)?].parent?;
+        Some(&self.nodes[p].lineage)
+    }
+
+    /// The direct sublineages, None if `lineage` is not in the tree.
+    pub fn children(
+        &self, lineage: &PangoLineage<HaplotypeBasename>
+    ) -> Option<impl Iterator<Item = &PangoLineage<HaplotypeBasename>>> {
+        let i = self.id(lineage)?;
+        Some(self.nodes[i].children.iter().map(move |c| &self.nodes[*c].lineage))
+    }
+
+    /// All sublineages, recursively, in pre-order; None if `lineage`
+    /// is not in the tree.
+    pub fn descendants(
+        &self, lineage: &PangoLineage<HaplotypeBasename>, include_self: bool
+    ) -> Option<Vec<&PangoLineage<HaplotypeBasename>>> {
+        let i = self.id(lineage)?;
+        let mut out = Vec::new();
+        let mut stack = vec![i];
+        while let Some(j) = stack.pop() {
+            if j != i || include_self {
+                out.push(&self.nodes[j].lineage);
+            }
+            stack.extend(self.nodes[j].children.iter().rev());
+        }
+        Some(out)
+    }
+
+    /// The number of ancestors, 0 for roots; None if `lineage` is not
+    /// in the tree.
+    pub fn depth(&self, lineage: &PangoLineage<HaplotypeBasename>) -> Option<usize> {
+        Some(self.nodes[self.id(lineage)?].depth)
+    }
+
+    fn lca_ids(&self, mut a: usize, mut b: usize) -> Option<usize> {
+        while self.nodes[a].depth > self.nodes[b].depth {
+            a = self.nodes[a].parent?;
+        }
+        while self.nodes[b].depth > self.nodes[a].depth {
+            b = self.nodes[b].parent?;
+        }
+        while a != b {
+            a = self.nodes[a].parent?;
+            b = self.nodes[b].parent?;
+        }
+        Some(a)
+    }
+
+    /// The deepest lineage that is an ancestor of (or equal to) all
+    /// of `lineages`. None if `lineages` is empty, one of them is not
+    /// in the tree, or they are in different trees (e.g. `A` and
+    /// `B`).
+    pub fn lowest_common_ancestor<'l>(
+        &self, lineages: impl IntoIterator<Item = &'l PangoLineage<HaplotypeBasename>>
+    ) -> Option<&PangoLineage<HaplotypeBasename>> {
+        let mut lineages = lineages.into_iter();
+        let mut lca = self.id(lineages.next()?)?;
+        for lineage in lineages {
+            lca = self.lca_ids(lca, self.id(lineage)?)?;
+        }
+        Some(&self.nodes[lca].lineage)
+    }
+
+    /// The number of edges on the path from `a` to `b`. None if one
+    /// of them is not in the tree, or they are in different trees.
+    pub fn distance(
+        &self, a: &PangoLineage<HaplotypeBasename>, b: &PangoLineage<HaplotypeBasename>
+    ) -> Option<usize> {
+        let (a, b) = (self.id(a)?, self.id(b)?);
+        let lca = self.lca_ids(a, b)?;
+        let depth = |i: usize| self.nodes[i].depth;
+        Some(depth(a) + depth(b) - 2 * depth(lca))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use super::*;
+
+    fn tree() -> (LineageAliases, LineageTree) {
+        let aliases = LineageAliases::from_alias_key_json(&jzon::parse(r#"{
+            "A": "", "B": "",
+            "BA": "B.1.1.529",
+            "BM": "B.1.1.529.2.75.3",
+            "Q": "B.1.1.7",
+            "XBB": ["BA.2.10.1.1", "BM.1.1.1"]
+        }"#).unwrap()).unwrap();
+        let tree = LineageTree::new(&aliases, [
+            "A", "A.1", "B", "B.1", "B.1.1.7", "Q.2", "Q.1", "Q.10", "*Q.3",
+            "B.1.1.529", "BA.1", "BA.2", "BA.2.75", "BM.1", "BM.1.1",
+            "XBB", "XBB.1.5"]).unwrap();
+        (aliases, tree)
+    }
+
+    #[test]
+    fn t_tree() {
+        let (aliases, tree) = tree();
+        let lin = |s: &str| aliases.canonicalize(s.try_into().unwrap());
+        let names = |ls: Vec<&PangoLineage<HaplotypeBasename>>| {
+            ls.into_iter().map(|l| aliases.compress(l).to_string()).collect::<Vec<_>>()
+        };
+        assert_eq!(names(tree.roots().collect()), ["A", "B", "XBB"]);
+        assert!(tree.contains(&lin("BA.2.75.3")));
+        assert!(tree.contains(&lin("B.1.1")));
+        assert!(! tree.contains(&lin("Q.3")));
+        assert!(! tree.contains(&lin("BA.3")));
+        assert_eq!(tree.len(), 19);
+
+        assert_eq!(tree.parent(&lin("BM.1")).unwrap().to_string(), "B.1.1.529.2.75.3");
+        assert_eq!(tree.parent(&lin("B")), None);
+        assert_eq!(tree.parent(&lin("BA.3")), None);
+        assert_eq!(names(tree.children(&lin("B.1.1.7")).unwrap().collect()),
+                   ["Q.1", "Q.2", "Q.10"]);
+        assert_eq!(names(tree.descendants(&lin("BA.2"), true).unwrap()),
+                   ["BA.2", "BA.2.75", "BA.2.75.3", "BM.1", "BM.1.1"]);
+        assert_eq!(names(tree.descendants(&lin("Q.1"), false).unwrap()), [] as [&str; 0]);
+        assert_eq!(tree.depth(&lin("B")), Some(0));
+        assert_eq!(tree.depth(&lin("BM.1.1")), Some(8));
+        assert_eq!(tree.depth(&lin("XBB.1.5")), Some(2));
+
+        let lca = |ls: &[&str]| {
+            let ls: Vec<_> = ls.iter().map(|s| lin(s)).collect();
+            tree.lowest_common_ancestor(&ls).map(|l| aliases.compress(l).to_string())
+        };
+        assert_eq!(lca(&["BM.1.1", "BA.1"]).unwrap(), "B.1.1.529");
+        assert_eq!(lca(&["BM.1.1", "BA.2", "BM.1"]).unwrap(), "BA.2");
+        assert_eq!(lca(&["Q.1", "BA.1"]).unwrap(), "B.1.1");
+        assert_eq!(lca(&["Q.1"]).unwrap(), "Q.1");
+        assert_eq!(lca(&["Q.1", "A.1"]), None);
+        assert_eq!(lca(&["XBB.1.5", "BA.2"]), None);
+        assert_eq!(lca(&[]), None);
+
+        assert_eq!(tree.distance(&lin("BM.1"), &lin("BA.1")), Some(5));
+        assert_eq!(tree.distance(&lin("Q.1"), &lin("Q.1")), Some(0));
+        assert_eq!(tree.distance(&lin("Q.1"), &lin("B")), Some(4));
+        assert_eq!(tree.distance(&lin("A"), &lin("B")), None);
+    }
+
+    #[test]
+    fn t_invalid_names() {
+        let (aliases, _) = tree();
+        let tree = LineageTree::new(&aliases, ["B.1", "B..2", "Q.1"]).unwrap();
+        // B, B.1, B.1.1, B.1.1.7, B.1.1.7.1
+        assert_eq!(tree.len(), 5);
+        assert!(tree.contains(&aliases.canonicalize("Q.1".try_into().unwrap())));
+
+        let mut data = JsonValue::new_object();
+        for name in ["BA.1", "ba.x", "*B.1.1.7.3"] {
+            data.insert(name, jzon::object! { Lineage: name, Description: "" }).unwrap();
+        }
+        let tree = LineageTree::from_lineage_data(&aliases, &data).unwrap();
+        // B, B.1, B.1.1, B.1.1.529, B.1.1.529.1
+        assert_eq!(tree.len(), 5);
+    }
+}
@@ -58,6 +58,12 @@ pub struct Lineage {
 
 lazy_static!{
     static ref ALIASOF_RE: Regex = Regex::new(r"\b[Aa]lias +of +([A-Z]+(?:\.\d+)*)").unwrap();
+    static ref RECOMBINANTOF_RE: Regex = Regex::new(
+        concat!(r"\b[Rr]ecombinant +(?:lineage +)?(?:of|between|with +parental +lineages) +",
+                r"([A-Z]+(?:\.\d+)*\b(?:(?:, *|,? +and +| *& *)[A-Z]+(?:\.\d+)*\b)*)")).unwrap();
+    static ref REPLACEDBY_RE: Regex = Regex::new(
+        r"\b(?:[Rr]eassigned|[Rr]enamed|[Mm]erged|[Mm]oved|[Rr]eplaced) +(?:(?:to|into|with|by) +)?([A-Z]+(?:\.\d+)*)\b").unwrap();
+    static ref LINEAGE_RE: Regex = Regex::new(r"[A-Z]+(?:\.\d+)*").unwrap();
 }
 
 
@@ -70,4 +76,21 @@ impl Lineage {
         caps.next();
         caps.next().map(|c| c.unwrap().as_str())
     }
+
+    /// The parent lineages of a recombinant, extracted from the
+    /// `Description` text field (e.g. "Recombinant lineage of BJ.1
+    /// and BM.1.1.1"). Not 100% reliable either.
+    pub fn get_recombinant_of(&self) -> Option<Vec<&str>> {
+        let cap = RECOMBINANTOF_RE.captures(&self.description)?;
+        let parents = cap.get(1).expect("group 1 always participates").as_str();
+        Some(LINEAGE_RE.find_iter(parents).map(|m| m.as_str()).collect())
+    }
+
+    /// For a withdrawn lineage, the lineage that replaced it,
+    /// extracted from the `Description` text field (e.g. "Withdrawn:
+    /// reassigned to B.1.177.4"). Not 100% reliable either.
+    pub fn get_replaced_by(&self) -> Option<&str> {
+        let cap = REPLACEDBY_RE.captures(&self.description)?;
+        Some(cap.get(1).expect("group 1 always participates").as_str())
+    }
 }
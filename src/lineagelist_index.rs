@@ -2,20 +2,45 @@
 //! https://github.com/cov-lineages/lineages-website/raw/master/_data/lineage_data.full.json
 //! (see https://cov-lineages.org/lineage_list.html)
 
-use std::{collections::HashMap, fs::read_to_string, convert::{TryInto, TryFrom}, io::Write};
+use std::{collections::HashMap, convert::{TryInto, TryFrom}, io::Write};
 
-use anyhow::{Result, bail};
+use anyhow::{anyhow, bail, Context, Result};
 use itertools::Itertools;
+use jzon::JsonValue;
 use kstring::KString;
 
-use crate::{lineagelist::Lineage,
+use crate::{compressed_io::read_to_string,
+            lineagelist::Lineage,
             pangolineage::{PangoLineage, HaplotypeBasename, BaseName,
-                           UndeterminedBaseName, Subpath},
+                           UndeterminedBaseName, DeterminedBaseName, Subpath, LineageName,
+                           LineageQuery, LineagePattern},
             easyjson::{EasyJsonValue, EasyObject}};
 
-/// An index of all aliases mentioned in the `lineage_data.json` file.
+/// An index of all aliases mentioned in the `lineage_data.json` file,
+/// and of the parents of recombinant lineages.
 #[derive(Debug)]
-pub struct LineageAliases(HashMap<KString, PangoLineage<HaplotypeBasename>>);
+pub struct LineageAliases {
+    aliases: HashMap<KString, PangoLineage<HaplotypeBasename>>,
+    /// Recombinant base name (e.g. `XBB`) -> its parent lineages,
+    /// canonicalized
+    recombinants: HashMap<KString, Vec<PangoLineage<HaplotypeBasename>>>,
+    /// Withdrawn name without the `*` (e.g. `J.1`) -> its details
+    withdrawn: HashMap<PangoLineage<UndeterminedBaseName>, Withdrawn>,
+    /// Canonical withdrawn lineage -> the lineage it was reassigned to,
+    /// to skip withdrawn ancestors in `withdrawn_replacement`
+    withdrawn_canonical: HashMap<PangoLineage<HaplotypeBasename>,
+                                 Option<PangoLineage<HaplotypeBasename>>>,
+    /// Canonical path -> alias, the inverse of `aliases`
+    compressions: HashMap<PangoLineage<HaplotypeBasename>, KString>,
+}
+
+#[derive(Debug)]
+struct Withdrawn {
+    /// The withdrawn name itself, canonicalized
+    canonical: PangoLineage<HaplotypeBasename>,
+    /// The lineage it was reassigned to, if given
+    replaced_by: Option<PangoLineage<HaplotypeBasename>>,
+}
 
 impl LineageAliases {
     pub fn from_file(path: &str) -> Result<LineageAliases> {
@@ -23,27 +48,42 @@ impl LineageAliases {
         //     File::open(path)?)?;
         // ^ 200x slower than jzon, so:
 
-        let inp = read_to_string(path)?;
-        let data = jzon::parse(&inp)?;
+        (|| -> Result<_> {
+            let inp = read_to_string(path)?;
+            Self::from_json(&jzon::parse(&inp)?)
+        })().with_context(|| anyhow!("reading lineage data from {path:?}"))
+    }
+
+    /// Build the index from the parsed contents of a
+    /// `lineage_data.json` file.
+    pub fn from_json(data: &JsonValue) -> Result<LineageAliases> {
         let raw = {
             let mut raw: HashMap<KString, Lineage> = Default::default();
             for (full_nam, lin_raw) in data.object()?.iter() {
                 let o = lin_raw.object()?;
                 let l = Lineage {
                     lineage: o.xget("Lineage")?.string()?.into(),
-                    description: o.xget("Description")?.string()?.into()
+                    description: o.xget("Description")?.string()?
                 };
                 raw.insert(KString::from_ref(full_nam), l);
             }
             raw
         };
-        
+
         let mut tbl = HashMap::new();
+        // (recombinant base name, parents as written)
+        let mut recombinants_raw = Vec::new();
+        let mut withdrawn_raw = Vec::new();
         for (full_nam, lin) in raw.iter() {
             assert_eq!(full_nam.as_str(), lin.lineage.as_str());
-            if full_nam.as_str().chars().next() == Some('*') {
-                // e.g. "*J.1", recalled names; PangoLineage won't
-                // currently parse them, thus skip
+            if full_nam.as_str().starts_with('*') {
+                // e.g. "*J.1", recalled names; they don't define
+                // aliases, but are resolved further down
+                match LineageName::try_from(full_nam.as_str()) {
+                    Ok(name) => withdrawn_raw.push(
+                        (name.lineage, lin.get_alias_of(), lin.get_replaced_by())),
+                    Err(e) => eprintln!("ignoring withdrawn {:?}: {e}", full_nam.as_str()),
+                }
                 continue;
             }
             // dbg!(&full_nam);
@@ -73,6 +113,10 @@ impl LineageAliases {
 
                 let key = KString::from_ref(shortened.0.as_str());
                 let value = PangoLineage::new(canonical.0, Subpath::new(canonical_path.into()));
+                if canonical_path.is_empty() && value.0.as_str() == key.as_str() {
+                    // e.g. "XBB.1.5" described as "Alias of XBB.1.5"
+                    continue;
+                }
                 if let Some(old) = tbl.get(&key) {
                     if old != &value {
                         bail!("alias {:?} previously defined as {:?}, now {:?}",
@@ -81,16 +125,182 @@ impl LineageAliases {
                 } else {
                     tbl.insert(key, value);
                 }
+            } else if let Some(parents) = lin.get_recombinant_of() {
+                let lineage: PangoLineage<UndeterminedBaseName> =
+                    match lin.lineage.as_str().try_into() {
+                        Ok(l) => l,
+                        Err(e) => {
+                            eprintln!("ignoring recombinant {:?}: {e}", lin.lineage.as_str());
+                            continue
+                        }
+                    };
+                // Only the root (e.g. "XBB") defines the parents
+                if lineage.1.as_ref().is_empty() {
+                    recombinants_raw.push((lineage.0, parents));
+                }
             }
         }
-        
-        Ok(Self(tbl))
+
+        let mut slf = Self::new(tbl);
+        slf.add_recombinants(recombinants_raw);
+        for (lineage, alias_of, replaced_by) in withdrawn_raw {
+            let canonical = match alias_of.map(PangoLineage::try_from).transpose() {
+                Ok(Some(alias_of)) => alias_of.force_into_canonicalization(),
+                Ok(None) => slf.canonicalize(lineage.clone()),
+                Err(e) => {
+                    eprintln!("ignoring withdrawn {:?}: invalid alias: {e}", lineage.to_string());
+                    continue
+                }
+            };
+            let replaced_by = match replaced_by.map(PangoLineage::try_from).transpose() {
+                Ok(replaced_by) => replaced_by.map(|l| slf.canonicalize(l)),
+                Err(e) => {
+                    eprintln!("withdrawn {:?}: ignoring replacement: {e}", lineage.to_string());
+                    None
+                }
+            };
+            slf.withdrawn_canonical.insert(canonical.clone(), replaced_by.clone());
+            slf.withdrawn.insert(lineage, Withdrawn { canonical, replaced_by });
+        }
+        Ok(slf)
+    }
+
+    fn new(aliases: HashMap<KString, PangoLineage<HaplotypeBasename>>) -> Self {
+        let mut compressions = HashMap::new();
+        for (alias, canonical) in &aliases {
+            // Choose deterministically if there are several aliases
+            // for the same path: the smallest, see `compress`
+            let old = compressions.entry(canonical.clone()).or_insert_with(|| alias.clone());
+            if alias < old {
+                *old = alias.clone();
+            }
+        }
+        Self {
+            aliases,
+            recombinants: HashMap::new(),
+            withdrawn: HashMap::new(),
+            withdrawn_canonical: HashMap::new(),
+            compressions,
+        }
+    }
+
+    /// Read the official `alias_key.json` file from
+    /// https://github.com/cov-lineages/pango-designation
+    pub fn from_alias_key_file(path: &str) -> Result<LineageAliases> {
+        (|| -> Result<_> {
+            let inp = read_to_string(path)?;
+            Self::from_alias_key_json(&jzon::parse(&inp)?)
+        })().with_context(|| anyhow!("reading alias key from {path:?}"))
+    }
+
+    /// Build the index from the parsed contents of an
+    /// `alias_key.json` file, which maps each alias to the canonical
+    /// path it stands for, to a list of parents for recombinants, or
+    /// to "" for the original haplotypes.
+    pub fn from_alias_key_json(data: &JsonValue) -> Result<LineageAliases> {
+        let mut aliases = HashMap::new();
+        let mut recombinants_raw = Vec::new();
+        for (alias, value) in data.object()?.iter() {
+            (|| -> Result<_> {
+                let basename = UndeterminedBaseName::new(KString::from_ref(alias))?;
+                match value {
+                    JsonValue::Array(parents) => {
+                        let parents = parents.iter().map(
+                            // Parents may be given as e.g. "BA.2*"
+                            |p| Ok(p.str()?.trim_end_matches('*')))
+                            .collect::<Result<Vec<_>>>()?;
+                        recombinants_raw.push((basename, parents));
+                    }
+                    _ => {
+                        let canonicalstr = value.str()?;
+                        if ! canonicalstr.is_empty() {
+                            let canonical: PangoLineage<HaplotypeBasename> =
+                                PangoLineage::try_from(canonicalstr)?
+                                .force_into_canonicalization();
+                            aliases.insert(basename.into_kstring(), canonical);
+                        }
+                    }
+                }
+                Ok(())
+            })().with_context(|| anyhow!("alias {alias:?}"))?;
+        }
+        let mut slf = Self::new(aliases);
+        slf.add_recombinants(recombinants_raw);
+        Ok(slf)
+    }
+
+    /// The parents are usually given using aliases, thus this is to
+    /// be called after all aliases are known.
+    fn add_recombinants(&mut self, recombinants_raw: Vec<(UndeterminedBaseName, Vec<&str>)>) {
+        for (basename, parents) in recombinants_raw {
+            let mut canonical_parents = Vec::new();
+            for parent in parents {
+                match PangoLineage::try_from(parent) {
+                    Ok(parent) => canonical_parents.push(self.canonicalize(parent)),
+                    Err(e) => eprintln!("recombinant {:?}: ignoring parent {parent:?}: {e}",
+                                        basename.as_str()),
+                }
+            }
+            self.recombinants.insert(basename.into_kstring(), canonical_parents);
+        }
+    }
+
+    /// Compare the aliases and recombinant parents with those in
+    /// `other` (e.g. from `alias_key.json` vs. from
+    /// `lineage_data.json`), returning a description of each
+    /// disagreement, sorted by alias. `names` are used to refer to
+    /// self and other.
+    pub fn cross_check(&self, other: &LineageAliases, names: (&str, &str)) -> Vec<String> {
+        fn compare<V: PartialEq>(
+            a: &HashMap<KString, V>, b: &HashMap<KString, V>,
+            show: impl Fn(&V) -> String,
+            names: (&str, &str),
+            out: &mut Vec<(KString, String)>
+        ) {
+            for key in a.keys().chain(b.keys()).unique() {
+                let msg = match (a.get(key), b.get(key)) {
+                    (Some(va), Some(vb)) => if va == vb {
+                        continue
+                    } else {
+                        format!("{} in {}, {} in {}", show(va), names.0, show(vb), names.1)
+                    },
+                    (Some(va), None) =>
+                        format!("{} in {}, missing in {}", show(va), names.0, names.1),
+                    (None, Some(vb)) =>
+                        format!("missing in {}, {} in {}", names.0, show(vb), names.1),
+                    (None, None) => unreachable!(),
+                };
+                out.push((key.clone(), msg));
+            }
+        }
+        let mut out = Vec::new();
+        compare(&self.aliases, &other.aliases,
+                |l| l.to_string(),
+                names, &mut out);
+        compare(&self.recombinants, &other.recombinants,
+                |ps| format!("recombinant of {}", ps.iter().map(|p| p.to_string()).join(", ")),
+                names, &mut out);
+        out.sort();
+        out.into_iter().map(|(key, msg)| format!("{}: {msg}", key.as_str())).collect()
     }
 
     pub fn print<W: Write>(&self, mut outp: W) -> Result<()> {
-        for alias in self.0.keys().sorted() {
-            let val = self.0.get(alias).unwrap();
-            writeln!(&mut outp, "{} = {}", alias.as_str(), val.to_string())?;
+        for alias in self.aliases.keys().sorted() {
+            let val = self.aliases.get(alias).unwrap();
+            writeln!(&mut outp, "{} = {val}", alias.as_str())?;
+        }
+        for name in self.recombinants.keys().sorted() {
+            let parents = self.recombinants.get(name).unwrap();
+            writeln!(&mut outp, "{} = recombinant of {}", name.as_str(),
+                     parents.iter().map(|p| p.to_string()).join(", "))?;
+        }
+        for name in self.withdrawn.keys().sorted() {
+            let w = self.withdrawn.get(name).unwrap();
+            match &w.replaced_by {
+                Some(l) => writeln!(&mut outp, "*{name} = withdrawn {}, replaced by {l}",
+                                    w.canonical)?,
+                None => writeln!(&mut outp, "*{name} = withdrawn {}", w.canonical)?,
+            }
         }
         Ok(())
     }
@@ -99,7 +309,29 @@ impl LineageAliases {
     /// lineage path based on the original haplo types that they were
     /// defined for.
     pub fn get(&self, key: &UndeterminedBaseName) -> Option<&PangoLineage<HaplotypeBasename>> {
-        self.0.get(key.as_kstring())
+        self.aliases.get(key.as_kstring())
+    }
+
+    /// Find out whether `basename` is an alias.
+    pub fn determine(&self, basename: UndeterminedBaseName) -> DeterminedBaseName {
+        let is_alias = self.aliases.contains_key(basename.as_kstring());
+        basename.into_determined(is_alias)
+    }
+
+    /// `determine` applied to the base name of `lineage`.
+    pub fn determine_lineage(
+        &self, lineage: PangoLineage<UndeterminedBaseName>
+    ) -> PangoLineage<DeterminedBaseName> {
+        let PangoLineage(basename, subpath) = lineage;
+        PangoLineage::new(self.determine(basename), subpath)
+    }
+
+    /// The parent lineages of the recombinant with the given base
+    /// name, or None if it isn't a known recombinant.
+    pub fn recombinant_parents(
+        &self, basename: &HaplotypeBasename
+    ) -> Option<&[PangoLineage<HaplotypeBasename>]> {
+        self.recombinants.get(basename.as_kstring()).map(|v| &**v)
     }
 
     /// Resolve aliases to the full paths based on the original haplo
@@ -115,4 +347,388 @@ impl LineageAliases {
                               inp.1)
         }
     }
+
+    /// For a withdrawn lineage name, the current lineage its records
+    /// should be classified as: the lineage it was reassigned to if
+    /// known, otherwise its parent, repeatedly while that is withdrawn
+    /// too. None for names that aren't withdrawn, or if no lineage
+    /// that isn't withdrawn is reached (e.g. for withdrawn base names
+    /// without a known replacement).
+    pub fn withdrawn_replacement(&self, name: &LineageName) -> Option<PangoLineage<HaplotypeBasename>> {
+        if ! name.withdrawn {
+            return None
+        }
+        let mut lineage = match self.withdrawn.get(&name.lineage) {
+            Some(w) => w.canonical.clone(),
+            // Not listed in the lineage data
+            None => self.canonicalize(name.lineage.clone()),
+        };
+        // Bounds the walk if replacements form a cycle
+        for _ in 0..=self.withdrawn_canonical.len() {
+            lineage = match self.withdrawn_canonical.get(&lineage) {
+                Some(Some(replaced_by)) => replaced_by.clone(),
+                _ => lineage.parent()?,
+            };
+            if ! self.withdrawn_canonical.contains_key(&lineage) {
+                return Some(lineage)
+            }
+        }
+        None
+    }
+
+    /// Like `canonicalize`, but also accepting withdrawn names,
+    /// which are replaced according to `withdrawn_replacement` (or
+    /// kept, if there's no replacement).
+    pub fn canonicalize_name(&self, name: LineageName) -> PangoLineage<HaplotypeBasename> {
+        if let Some(l) = self.withdrawn_replacement(&name) {
+            l
+        } else {
+            self.canonicalize(name.lineage)
+        }
+    }
+
+    /// The inverse of `canonicalize`: the conventional short name of
+    /// a lineage, using the alias for the longest prefix of its path
+    /// that has one, e.g. `B.1.1.529.2.75.3.1` -> `BM.1`. At least
+    /// one path element is always left after an alias, as an alias
+    /// on its own isn't a lineage name (`B.1.1.529.2.75.3` stays
+    /// `BA.2.75.3`). If several aliases stand for the same path, the
+    /// alphabetically smallest is used.
+    pub fn compress(&self, lineage: &PangoLineage<HaplotypeBasename>) -> PangoLineage<UndeterminedBaseName> {
+        let path = lineage.1.as_ref();
+        for prefix_len in (1..path.len()).rev() {
+            let prefix = PangoLineage::new(lineage.0.clone(),
+                                           Subpath::new(path[..prefix_len].into()));
+            if let Some(alias) = self.compressions.get(&prefix) {
+                return PangoLineage::new(
+                    UndeterminedBaseName::new(alias.clone()).expect("was checked when loading"),
+                    Subpath::new(path[prefix_len..].into()))
+            }
+        }
+        PangoLineage::new(lineage.0.clone().into_undetermined(), lineage.1.clone())
+    }
+
+    /// Whether `lineage` descends from `ancestor`, either directly
+    /// (see `PangoLineage::is_ancestor_of`) or via recombination:
+    /// `ancestor` is an ancestor of (or is) a parent of the
+    /// recombinant that `lineage` belongs to, recursively. E.g.
+    /// `XBB.1.5` descends from `XBB` directly, and from `BA.2` via
+    /// recombination.
+    pub fn descends_from(
+        &self,
+        lineage: &PangoLineage<HaplotypeBasename>,
+        ancestor: &PangoLineage<HaplotypeBasename>,
+        include_self: bool
+    ) -> bool {
+        // The depth bound guards against cycles in broken data
+        self.descends_from_bounded(lineage, ancestor, include_self, self.recombinants.len())
+    }
+
+    fn descends_from_bounded(
+        &self,
+        lineage: &PangoLineage<HaplotypeBasename>,
+        ancestor: &PangoLineage<HaplotypeBasename>,
+        include_self: bool,
+        max_depth: usize,
+    ) -> bool {
+        if ancestor.is_ancestor_of(lineage, include_self) {
+            return true
+        }
+        if max_depth == 0 {
+            return false
+        }
+        self.recombinant_parents(&lineage.0).is_some_and(|parents| {
+            parents.iter().any(
+                |parent| self.descends_from_bounded(parent, ancestor, true, max_depth - 1))
+        })
+    }
+
+    /// Resolve the aliases in the patterns of `query`, giving a
+    /// predicate for canonicalized lineages.
+    pub fn compile_query(&self, query: &LineageQuery) -> CompiledLineageQuery {
+        let compile = |patterns: &[LineagePattern]| patterns.iter().map(|p| match p {
+            LineagePattern::Exact(l) =>
+                (self.canonicalize(l.clone()), false),
+            LineagePattern::WithSublineages(l) =>
+                (self.canonicalize(l.clone()), true),
+        }).collect();
+        CompiledLineageQuery {
+            include: compile(&query.include),
+            exclude: compile(&query.exclude),
+        }
+    }
+}
+
+
+/// A `LineageQuery` with the aliases resolved, see
+/// `LineageAliases::compile_query`.
+#[derive(Debug, Clone)]
+pub struct CompiledLineageQuery {
+    /// (lineage, with sublineages)
+    include: Vec<(PangoLineage<HaplotypeBasename>, bool)>,
+    exclude: Vec<(PangoLineage<HaplotypeBasename>, bool)>,
+}
+
+impl CompiledLineageQuery {
+    pub fn matches(&self, lineage: &PangoLineage<HaplotypeBasename>) -> bool {
+        let matches_any = |patterns: &[(PangoLineage<HaplotypeBasename>, bool)]| {
+            patterns.iter().any(|(l, with_sublineages)| if *with_sublineages {
+                l.is_ancestor_of(lineage, true)
+            } else {
+                l == lineage
+            })
+        };
+        matches_any(&self.include) && ! matches_any(&self.exclude)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pangolineage::AliasBaseName;
+
+    fn lineage_data() -> JsonValue {
+        let mut data = JsonValue::new_object();
+        for (name, description) in [
+            ("B.1.1.529", "Omicron"),
+            ("BA.2", "Alias of B.1.1.529.2"),
+            ("BA.2.10.1", "Alias of B.1.1.529.2.10.1"),
+            ("BJ.1", "Alias of B.1.1.529.2.10.1.1, India"),
+            ("BA.2.75", "Alias of B.1.1.529.2.75"),
+            ("BM.1.1.1", "Alias of B.1.1.529.2.75.3.1.1.1"),
+            ("XBB", "Recombinant lineage of BJ.1 and BM.1.1.1, breakpoint S:22897-22941"),
+            ("XBB.1.5", "Alias of XBB.1.5, USA"),
+            ("XBB.1.9.2", "Alias of XBB.1.9.2"),
+            ("EG.5", "Alias of XBB.1.9.2.5, sublineage of XBB.1.9.2"),
+            ("XBL", "Recombinant lineage of XBB.1.5 and BA.2.75"),
+            ("B.1.1.7", "Alpha"),
+            ("Q.1", "Alias of B.1.1.7.1"),
+            ("*Q.5", "Withdrawn: Alias of B.1.1.7.5"),
+            ("*B.1.1.7.3", "Withdrawn: reassigned to Q.1"),
+            ("*J.1", "Withdrawn"),
+            ("*Q.6", "Withdrawn: reassigned to Q.5"),
+            ("*Q.7", "Withdrawn: reassigned to Q.8"),
+            ("*Q.8", "Withdrawn: reassigned to Q.7"),
+        ] {
+            data.insert(name, jzon::object! {
+                Lineage: name,
+                Description: description
+            }).unwrap();
+        }
+        data
+    }
+
+    #[test]
+    fn t_recombinants() {
+        let aliases = LineageAliases::from_json(&lineage_data()).unwrap();
+        let lin = |s: &str| aliases.canonicalize(s.try_into().unwrap());
+        let parents = |s: &str| aliases.recombinant_parents(&lin(s).0).map(
+            |ps| ps.iter().map(|p| p.to_string()).collect::<Vec<_>>());
+        assert_eq!(parents("XBB").unwrap(),
+                   ["B.1.1.529.2.10.1.1", "B.1.1.529.2.75.3.1.1.1"]);
+        assert_eq!(parents("XBB.1.5").unwrap().len(), 2);
+        assert_eq!(parents("BA.2"), None);
+
+        assert_eq!(lin("EG.5.1").to_string(), "XBB.1.9.2.5.1");
+        assert!(lin("XBB").is_ancestor_of(&lin("XBB.1.5"), false));
+        assert!(lin("XBB").is_ancestor_of(&lin("EG.5.1"), false));
+        assert!(! lin("BA.2").is_ancestor_of(&lin("XBB.1.5"), true));
+
+        let descends = |a: &str, b: &str| aliases.descends_from(&lin(a), &lin(b), true);
+        assert!(descends("XBB.1.5", "XBB"));
+        assert!(descends("EG.5", "BA.2"));
+        assert!(descends("XBB.1.5", "BA.2.75"));
+        assert!(descends("XBB", "BJ.1"));
+        assert!(! descends("XBB", "BJ.1.1"));
+        assert!(! descends("XBB", "BA.1"));
+        // Recursively
+        assert!(descends("XBL.1", "XBB"));
+        assert!(descends("XBL", "BA.2.10"));
+        assert!(! descends("XBB", "XBL"));
+        assert!(! aliases.descends_from(&lin("XBB"), &lin("XBB"), false));
+    }
+
+    #[test]
+    fn t_withdrawn() {
+        let aliases = LineageAliases::from_json(&lineage_data()).unwrap();
+        let name = |s: &str| -> LineageName { s.try_into().unwrap() };
+        let replacement = |s: &str| aliases.withdrawn_replacement(&name(s)).map(
+            |l| l.to_string());
+        assert_eq!(replacement("*B.1.1.7.3").unwrap(), "B.1.1.7.1");
+        assert_eq!(replacement("*Q.5").unwrap(), "B.1.1.7");
+        assert_eq!(replacement("*J.1").unwrap(), "J");
+        assert_eq!(replacement("*J"), None);
+        assert_eq!(replacement("Q.1"), None);
+        // Not in the lineage data, but the alias is known
+        assert_eq!(replacement("*Q.2.3").unwrap(), "B.1.1.7.2");
+
+        // Withdrawn ancestors and replacements are skipped
+        assert_eq!(replacement("*Q.5.1").unwrap(), "B.1.1.7");
+        assert_eq!(replacement("*Q.5.1.2").unwrap(), "B.1.1.7.5.1");
+        assert_eq!(replacement("*Q.6").unwrap(), "B.1.1.7");
+        assert_eq!(replacement("*Q.7"), None);
+
+        assert_eq!(aliases.canonicalize_name(name("*Q.5.1")).to_string(), "B.1.1.7");
+        assert_eq!(aliases.canonicalize_name(name("Q.1")).to_string(), "B.1.1.7.1");
+        assert_eq!(aliases.canonicalize_name(name("*J")).to_string(), "J");
+
+        // Unparseable withdrawn entries are skipped
+        let mut data = lineage_data();
+        for (name, description) in [
+            ("*Q.x", "Withdrawn"),
+            ("*Q.9", "Withdrawn: Alias of B.99999999999999999999"),
+        ] {
+            data.insert(name, jzon::object! {
+                Lineage: name,
+                Description: description
+            }).unwrap();
+        }
+        let aliases = LineageAliases::from_json(&data).unwrap();
+        assert_eq!(aliases.withdrawn_replacement(&name("*Q.9")).unwrap().to_string(),
+                   "B.1.1.7");
+    }
+
+    #[test]
+    fn t_alias_key() {
+        let alias_key = jzon::parse(r#"{
+            "A": "", "B": "",
+            "BA": "B.1.1.529",
+            "BJ": "B.1.1.529.2.10.1",
+            "BM": "B.1.1.529.2.75.4",
+            "EG": "XBB.1.9.2",
+            "Q": "B.1.1.7",
+            "XBB": ["BJ.1", "BM.1.1.1"],
+            "XBC": ["BA.2*", "B.1.617.2*"]
+        }"#).unwrap();
+        let key_aliases = LineageAliases::from_alias_key_json(&alias_key).unwrap();
+        let lin = |s: &str| key_aliases.canonicalize(s.try_into().unwrap());
+        assert_eq!(lin("BA.2.75").to_string(), "B.1.1.529.2.75");
+        assert_eq!(lin("EG.5.1").to_string(), "XBB.1.9.2.5.1");
+        assert_eq!(lin("B.1").to_string(), "B.1");
+        assert_eq!(key_aliases.recombinant_parents(&lin("XBC").0).unwrap()
+                   .iter().map(|p| p.to_string()).collect::<Vec<_>>(),
+                   ["B.1.1.529.2", "B.1.617.2"]);
+        assert!(key_aliases.descends_from(&lin("EG.5"), &lin("BA.2.75"), false));
+
+        assert!(LineageAliases::from_alias_key_json(
+            &jzon::parse(r#"{"BA": 1}"#).unwrap()).is_err());
+
+        let data_aliases = LineageAliases::from_json(&lineage_data()).unwrap();
+        assert_eq!(
+            key_aliases.cross_check(&data_aliases, ("alias_key", "lineage_data")),
+            [
+                "BM: B.1.1.529.2.75.4 in alias_key, B.1.1.529.2.75.3 in lineage_data",
+                // (follows from BM)
+                "XBB: recombinant of B.1.1.529.2.10.1.1, B.1.1.529.2.75.4.1.1.1 in alias_key, \
+                 recombinant of B.1.1.529.2.10.1.1, B.1.1.529.2.75.3.1.1.1 in lineage_data",
+                "XBC: recombinant of B.1.1.529.2, B.1.617.2 in alias_key, \
+                 missing in lineage_data",
+                "XBL: missing in alias_key, recombinant of XBB.1.5, \
+                 B.1.1.529.2.75 in lineage_data",
+            ]);
+    }
+
+    /// Check that compressing the canonical form gives the original
+    /// name back, for all non-withdrawn names in `data`.
+    fn check_compress_round_trip(data: &JsonValue) -> LineageAliases {
+        let aliases = LineageAliases::from_json(data).unwrap();
+        let mut checked = 0;
+        for (name, _) in data.object().unwrap().iter() {
+            let name: LineageName = name.try_into().unwrap();
+            if name.withdrawn {
+                continue;
+            }
+            let canonical = aliases.canonicalize(name.lineage.clone());
+            let compressed = aliases.compress(&canonical);
+            assert_eq!(compressed, name.lineage);
+            assert_eq!(aliases.canonicalize(compressed), canonical);
+            checked += 1;
+        }
+        assert!(checked > 10);
+        aliases
+    }
+
+    #[test]
+    fn t_compress() {
+        // Only a sample of lineage_data.full.json (with only the
+        // fields used) is checked in; the full list is only checked
+        // if its path is given in the `LINEAGE_DATA_JSON` env var
+        let fixture = jzon::parse(include_str!("../test-data/lineage_data.json")).unwrap();
+        let fixture_aliases = check_compress_round_trip(&fixture);
+        let compress = |s: &str| fixture_aliases.compress(
+            &fixture_aliases.canonicalize(s.try_into().unwrap())).to_string();
+        assert_eq!(compress("B.1.1.529.2.86.1.1.11.1.3.1.1.5"), "KP.3.1.1.5");
+        assert_eq!(compress("B.1.1.529.5.3.1.1.1.1.1.1"), "BQ.1.1");
+        assert_eq!(compress("XBB.1.9.2.5.1.1.3"), "HK.3");
+        assert_eq!(compress("B.1.617.2.4"), "AY.4");
+        if let Ok(path) = std::env::var("LINEAGE_DATA_JSON") {
+            check_compress_round_trip(&jzon::parse(&read_to_string(path).unwrap()).unwrap());
+        }
+
+        let aliases = check_compress_round_trip(&lineage_data());
+        let lin = |s: &str| aliases.canonicalize(s.try_into().unwrap());
+        let compress = |s: &str| aliases.compress(&lin(s)).to_string();
+        assert_eq!(compress("B.1.1.529.2.75.3.1"), "BM.1");
+        assert_eq!(compress("B.1.1.529.2.75.3"), "BA.2.75.3");
+        assert_eq!(compress("B.1.1.529.2.75.3.1.1.1.7"), "BM.1.1.1.7");
+        assert_eq!(compress("XBB.1.9.2.5.1"), "EG.5.1");
+        assert_eq!(compress("B.1.1"), "B.1.1");
+        assert_eq!(compress("B"), "B");
+    
+        // With several aliases for the same path, the alphabetically
+        // first one is used, so names using the others don't round
+        // trip
+        let aliases = LineageAliases::from_alias_key_json(&jzon::parse(r#"{
+            "B": "", "BA": "B.1.1.529", "ZA": "B.1.1.529", "CA": "B.1.1.529.2.1"
+        }"#).unwrap()).unwrap();
+        let compress = |s: &str| aliases.compress(
+            &aliases.canonicalize(s.try_into().unwrap())).to_string();
+        assert_eq!(compress("ZA.2"), "BA.2");
+        assert_eq!(compress("BA.2"), "BA.2");
+        assert_eq!(compress("ZA.2.1.5"), "CA.5");
+    }
+
+    #[test]
+    fn t_determine() {
+        let aliases = LineageAliases::from_json(&lineage_data()).unwrap();
+        let determine = |s: &str| aliases.determine_lineage(s.parse().unwrap());
+        let l = determine("BA.2.75");
+        assert!(l.0.is_alias());
+        assert_eq!(l.to_string(), "BA.2.75");
+        assert_eq!(l.0, DeterminedBaseName::Alias(AliasBaseName::new("BA".into()).unwrap()));
+        assert!(! determine("B.1.1.529").0.is_alias());
+        assert!(! determine("XBB.1").0.is_alias());
+        assert_eq!(determine("XBB.1").0,
+                   DeterminedBaseName::OriginalHaplotype(HaplotypeBasename::new("XBB".into()).unwrap()));
+    }
+
+    fn query_aliases() -> LineageAliases {
+        LineageAliases::from_alias_key_json(&jzon::parse(r#"{
+            "B": "", "BA": "B.1.1.529", "XBB": ["BA.2.10.1.1", "BA.2.75.3.1.1.1"],
+            "EG": "XBB.1.9.2"
+        }"#).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn t_query_matches() {
+        let aliases = query_aliases();
+        let matches = |query: &str, lineages: &[&str]| -> Vec<bool> {
+            let q = aliases.compile_query(&LineageQuery::try_from(query).unwrap());
+            lineages.iter().map(
+                |l| q.matches(&aliases.canonicalize((*l).try_into().unwrap()))).collect()
+        };
+        let lineages = ["BA.2", "B.1.1.529.2.1", "BA.2.75", "XBB", "XBB.1.5", "XBB.1.5.2",
+                        "EG.5", "B.1.1.7"];
+        assert_eq!(matches("BA.2", &lineages),
+                   [true, false, false, false, false, false, false, false]);
+        assert_eq!(matches("BA.2*", &lineages),
+                   [true, true, true, false, false, false, false, false]);
+        assert_eq!(matches("XBB* except XBB.1.5*", &lineages),
+                   [false, false, false, true, false, false, true, false]);
+        assert_eq!(matches("XBB+ except XBB.1.5, XBB.1.9.2*", &lineages),
+                   [false, false, false, true, false, true, false, false]);
+        assert_eq!(matches("B.1.1.7 or BA.2.75*", &lineages),
+                   [false, false, true, false, false, false, false, true]);
+    }
 }
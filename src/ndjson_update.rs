@@ -0,0 +1,844 @@
+//! Joining TSV rows into ndjson records, the core of the
+//! `ndjson-updater` binary. Several TSV files ("sources"), each with
+//! its own `MappingConfig`, can be joined in one pass.
+
+use std::{collections::{HashMap, HashSet}, io::Write, str::FromStr};
+
+use anyhow::{anyhow, bail, Context, Result};
+use jzon::JsonValue;
+
+use kstring::KString;
+
+use crate::{column_mapping::MappingConfig, jsonpath::JsonPath,
+            jsonsplice::{Edits, RawJson}, tsv::Tsv,
+            tsv_value::TsvValueParser};
+
+
+/// What to do with an ndjson record for which there is no TSV row.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissingRowPolicy {
+    /// Abort the run
+    Error,
+    /// Leave the record out of the output
+    Skip,
+    /// Write the record unchanged
+    Pass,
+    /// Set all mapped fields to null
+    Null,
+}
+
+impl FromStr for MissingRowPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "error" => MissingRowPolicy::Error,
+            "skip" => MissingRowPolicy::Skip,
+            "pass" => MissingRowPolicy::Pass,
+            "null" => MissingRowPolicy::Null,
+            _ => bail!("invalid missing row policy {s:?}, expecting one of \
+                        error, skip, pass, null")
+        })
+    }
+}
+
+/// What to do when two sources set the same field of a record to
+/// different values. Values set because of `MissingRowPolicy::Null`
+/// never conflict, they are only used if no other source sets the
+/// field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    Error,
+    /// The source given first wins
+    First,
+    /// The source given last wins
+    Last,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "error" => ConflictPolicy::Error,
+            "first" => ConflictPolicy::First,
+            "last" => ConflictPolicy::Last,
+            _ => bail!("invalid conflict policy {s:?}, expecting one of \
+                        error, first, last")
+        })
+    }
+}
+
+/// How many unused keys are shown by `UnusedRowPolicy::Report`.
+pub const REPORT_MAX_KEYS: usize = 20;
+
+/// What to do with TSV rows that were not used by any ndjson record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnusedRowPolicy {
+    Error,
+    /// Print the number of unused rows and some of their keys (see
+    /// `REPORT_MAX_KEYS`) to stderr
+    Report,
+    Ignore,
+}
+
+impl FromStr for UnusedRowPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "error" => UnusedRowPolicy::Error,
+            "report" => UnusedRowPolicy::Report,
+            "ignore" => UnusedRowPolicy::Ignore,
+            _ => bail!("invalid unused row policy {s:?}, expecting one of \
+                        error, report, ignore")
+        })
+    }
+}
+
+
+/// Join keys can be given as strings or numbers in the ndjson
+/// records; TSV cells are always strings.
+fn key_string(v: &JsonValue) -> Result<String> {
+    match v {
+        JsonValue::Short(s) => Ok(s.to_string()),
+        JsonValue::String(s) => Ok(s.clone()),
+        JsonValue::Number(n) => Ok(n.to_string()),
+        _ => bail!("expecting string or number as key, got: {}", v.dump())
+    }
+}
+
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RecordOutcome {
+    /// The record was updated from the TSV row with the given key
+    Updated(String),
+    /// There was no row for the given key, and the policy was applied
+    /// to the record
+    Missing(String, MissingRowPolicy),
+}
+
+impl RecordOutcome {
+    pub fn key(&self) -> &str {
+        match self {
+            RecordOutcome::Updated(key) => key,
+            RecordOutcome::Missing(key, _) => key,
+        }
+    }
+
+    /// Whether the record is to be written to the output.
+    pub fn is_written(&self) -> bool {
+        ! matches!(self, RecordOutcome::Missing(_, MissingRowPolicy::Skip))
+    }
+}
+
+/// The old and new value of a mapped field in a record.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldChange {
+    /// Index of the source that provided the new value
+    pub source: usize,
+    /// Index into `MappingConfig::columns` of the source
+    pub column: usize,
+    /// None if the field was missing
+    pub old: Option<JsonValue>,
+    pub new: JsonValue,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// The field was missing
+    New,
+    Same,
+    Changed,
+}
+
+impl FieldChange {
+    pub fn kind(&self) -> ChangeKind {
+        match &self.old {
+            None => ChangeKind::New,
+            Some(old) if *old == self.new => ChangeKind::Same,
+            Some(_) => ChangeKind::Changed,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct RecordUpdate {
+    /// One per source
+    pub outcomes: Vec<RecordOutcome>,
+    /// Only filled when change tracking is enabled
+    pub changes: Vec<FieldChange>,
+}
+
+impl RecordUpdate {
+    /// Whether the record is to be written to the output.
+    pub fn is_written(&self) -> bool {
+        self.outcomes.iter().all(|o| o.is_written())
+    }
+
+    /// Write the changes other than `ChangeKind::Same` as ndjson, with
+    /// the keys `key`, `field` (the full path), `old` (if the field
+    /// was present) and `new`.
+    pub fn write_diff<W: Write>(&self, sources: &[Source], mut outp: W) -> Result<()> {
+        for change in &self.changes {
+            if change.kind() == ChangeKind::Same {
+                continue
+            }
+            let mapping = &sources[change.source].mapping;
+            let mut d = JsonValue::new_object();
+            d.insert("key", self.outcomes[change.source].key())?;
+            d.insert("field", mapping.target.join(
+                &mapping.columns[change.column].field).to_string())?;
+            if let Some(old) = &change.old {
+                d.insert("old", old.clone())?;
+            }
+            d.insert("new", change.new.clone())?;
+            d.write(&mut outp)?;
+            outp.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+}
+
+
+/// A TSV file with its mapping.
+pub struct Source<'t> {
+    pub tsv: &'t Tsv,
+    pub mapping: MappingConfig,
+    index: HashMap<&'t str, usize>,
+    parser: TsvValueParser,
+    /// Index of the TSV column for each entry in `mapping.columns`
+    column_indices: Vec<usize>,
+}
+
+impl<'t> Source<'t> {
+    pub fn new(tsv: &'t Tsv, mapping: MappingConfig) -> Result<Self> {
+        let index = tsv.index_by(&mapping.tsv_key)?;
+        let column_indices = mapping.columns.iter().map(
+            |m| tsv.column_index(&m.column))
+            .collect::<Result<Vec<_>>>()?;
+        let parser = mapping.value_parser();
+        for (m, coli) in mapping.columns.iter().zip(&column_indices) {
+            parser.warn_invalid(m.column_type, tsv, *coli);
+        }
+        Ok(Source { tsv, mapping, index, parser, column_indices })
+    }
+
+    /// The keys of all TSV rows that are not in `used_keys`, in file
+    /// order.
+    pub fn unused_keys<'s>(&'s self, used_keys: &'s HashSet<String>)
+                           -> impl Iterator<Item = &'t str> + 's
+    {
+        let tsv = self.tsv;
+        let keyi = tsv.column_index(&self.mapping.tsv_key)
+            .expect("checked in new");
+        tsv.rows.iter().map(move |row| &row[keyi])
+            .filter(move |key| ! used_keys.contains(*key))
+    }
+}
+
+
+/// A value for a field, determined from one of the sources.
+struct FieldValue {
+    source: usize,
+    column: usize,
+    value: JsonValue,
+    /// From a matched row, as opposed to `MissingRowPolicy::Null`
+    from_row: bool,
+}
+
+/// The values to set, by target object and field name.
+type FieldValues = Vec<(JsonPath, KString, FieldValue)>;
+
+pub struct Updater<'t> {
+    sources: Vec<Source<'t>>,
+    missing_row: MissingRowPolicy,
+    conflicts: ConflictPolicy,
+    track_changes: bool,
+}
+
+impl<'t> Updater<'t> {
+    pub fn new(
+        sources: Vec<Source<'t>>, missing_row: MissingRowPolicy, conflicts: ConflictPolicy
+    ) -> Result<Self> {
+        if sources.is_empty() {
+            bail!("need at least one source")
+        }
+        Ok(Updater { sources, missing_row, conflicts, track_changes: false })
+    }
+
+    /// Record the old and new values of the mapped fields in
+    /// `RecordUpdate::changes`; costs a bit of time.
+    pub fn set_track_changes(&mut self, track_changes: bool) {
+        self.track_changes = track_changes;
+    }
+
+    pub fn sources(&self) -> &[Source<'t>] {
+        &self.sources
+    }
+
+    /// Look up the TSV row for `key` in source `sourcei` and add the
+    /// values for its mapped fields to `values`, resolving conflicts.
+    fn add_field_values(
+        &self, sourcei: usize, key: String,
+        values: &mut FieldValues
+    ) -> Result<RecordOutcome> {
+        let source = &self.sources[sourcei];
+        let mapping = &source.mapping;
+        let (outcome, row) = match source.index.get(&*key) {
+            Some(rowi) => (RecordOutcome::Updated(key), Some(*rowi)),
+            None => match self.missing_row {
+                MissingRowPolicy::Error =>
+                    bail!("unknown {:?} value {key:?} in {:?}", mapping.tsv_key, source.tsv.path),
+                MissingRowPolicy::Skip | MissingRowPolicy::Pass =>
+                    return Ok(RecordOutcome::Missing(key, self.missing_row)),
+                MissingRowPolicy::Null =>
+                    (RecordOutcome::Missing(key, self.missing_row), None),
+            }
+        };
+        for (column, (m, coli)) in mapping.columns.iter().zip(&source.column_indices).enumerate() {
+            let value = match row {
+                Some(rowi) => source.parser.parse_json(
+                    m.column_type, &source.tsv.rows[rowi][*coli], &m.column,
+                    source.tsv.line_number(rowi))?,
+                None => JsonValue::Null,
+            };
+            let new = FieldValue { source: sourcei, column, value, from_row: row.is_some() };
+            let existing = values.iter_mut().find(
+                |(target, field, _)| *target == mapping.target && field.as_str() == m.field);
+            match existing {
+                None => values.push((mapping.target.clone(), KString::from_ref(&m.field), new)),
+                Some((_, _, old)) => {
+                    let replace = match (old.from_row, new.from_row) {
+                        (false, _) => true,
+                        (true, false) => false,
+                        (true, true) => if old.value == new.value {
+                            false
+                        } else {
+                            match self.conflicts {
+                                ConflictPolicy::Error => bail!(
+                                    "conflicting values for {} from {:?}: {} and {:?}: {}",
+                                    mapping.target.join(&m.field),
+                                    self.sources[old.source].tsv.path, old.value.dump(),
+                                    source.tsv.path, new.value.dump()),
+                                ConflictPolicy::First => false,
+                                ConflictPolicy::Last => true,
+                            }
+                        }
+                    };
+                    if replace {
+                        *old = new;
+                    }
+                }
+            }
+        }
+        Ok(outcome)
+    }
+
+    /// Determine the field values from all sources, with `get_key`
+    /// retrieving the join key at the given path from the record.
+    fn field_values(
+        &self, get_key: impl Fn(&JsonPath) -> Result<JsonValue>
+    ) -> Result<(Vec<RecordOutcome>, FieldValues)> {
+        let mut outcomes = Vec::with_capacity(self.sources.len());
+        let mut values = Vec::new();
+        for (sourcei, source) in self.sources.iter().enumerate() {
+            let json_key = &source.mapping.json_key;
+            let key = (|| key_string(&get_key(json_key)?))().with_context(
+                || anyhow!("key at {json_key}"))?;
+            let outcome = self.add_field_values(sourcei, key, &mut values)?;
+            if ! outcome.is_written() {
+                // The record is skipped anyway
+                outcomes.push(outcome);
+                return Ok((outcomes, Vec::new()))
+            }
+            outcomes.push(outcome);
+        }
+        Ok((outcomes, values))
+    }
+
+    fn changes(
+        &self, values: &[(JsonPath, KString, FieldValue)],
+        old_value: impl Fn(&JsonPath, &str) -> Option<JsonValue>
+    ) -> Vec<FieldChange> {
+        if ! self.track_changes {
+            return Vec::new()
+        }
+        values.iter().map(|(target, field, v)| FieldChange {
+            source: v.source,
+            column: v.column,
+            old: old_value(target, field),
+            new: v.value.clone(),
+        }).collect()
+    }
+
+    /// Update `entry` in place from its TSV rows.
+    pub fn update_record(&self, entry: &mut JsonValue) -> Result<RecordUpdate> {
+        let (outcomes, values) = self.field_values(|path| Ok(path.get(entry)?.clone()))?;
+        let changes = self.changes(&values, |target, field| {
+            target.get(entry).ok()
+                .and_then(|t| t.as_object())
+                .and_then(|t| t.get(field)).cloned()
+        });
+        for (target, field, v) in values {
+            target.get_or_create_object_mut(entry)?.insert(&field, v.value);
+        }
+        Ok(RecordUpdate { outcomes, changes })
+    }
+
+    fn line_update(&self, raw: &RawJson) -> Result<(RecordUpdate, FieldValues)> {
+        let (outcomes, values) = self.field_values(
+            |path| raw.get_value(path)?.ok_or_else(|| anyhow!("missing key")))?;
+        let changes = self.changes(&values, |target, field| {
+            // A missing or non-object target means missing fields
+            raw.get_value(&target.join(field)).ok().flatten()
+        });
+        Ok((RecordUpdate { outcomes, changes }, values))
+    }
+
+    /// Like `update_line`, but without producing the output, e.g. for
+    /// dry runs.
+    pub fn check_line(&self, line: &str) -> Result<RecordUpdate> {
+        Ok(self.line_update(&RawJson::scan(line)?)?.0)
+    }
+
+    /// Update the JSON text of a record without reformatting it: only
+    /// the mapped fields are replaced or added, all other bytes are
+    /// copied unchanged (see `jsonsplice`). Returns None if the
+    /// record is not to be written.
+    pub fn update_line(&self, line: &str) -> Result<(RecordUpdate, Option<String>)> {
+        // Scanned once for all lookups and edits
+        let raw = RawJson::scan(line)?;
+        let (update, values) = self.line_update(&raw)?;
+        if ! update.is_written() {
+            return Ok((update, None))
+        }
+        let mut targets: Vec<&JsonPath> = Vec::new();
+        for (target, _, _) in &values {
+            if ! targets.contains(&target) {
+                targets.push(target);
+            }
+        }
+        let mut edits = Edits::default();
+        for target in targets {
+            let fields: Vec<_> = values.iter()
+                .filter(|(t, _, _)| t == target)
+                .map(|(_, field, v)| (field.as_str(), v.value.clone()))
+                .collect();
+            raw.set_fields(target, &fields, &mut edits)?;
+        }
+        let output = raw.apply(edits)?;
+        Ok((update, Some(output)))
+    }
+}
+
+
+/// Which TSV rows were used by written records, and how many records
+/// had no row, per source.
+#[derive(Debug)]
+pub struct RowUsage {
+    pub used_keys: Vec<HashSet<String>>,
+    pub num_missing: Vec<u64>,
+}
+
+impl RowUsage {
+    pub fn new(sources: &[Source]) -> Self {
+        RowUsage {
+            used_keys: vec![HashSet::new(); sources.len()],
+            num_missing: vec![0; sources.len()],
+        }
+    }
+
+    /// Account for the outcome of a record, in input order. Rows are
+    /// only marked used if the record is written; a row used by more
+    /// than one written record is an error.
+    pub fn add(&mut self, sources: &[Source], update: &RecordUpdate) -> Result<()> {
+        let is_written = update.is_written();
+        for (sourcei, outcome) in update.outcomes.iter().enumerate() {
+            match outcome {
+                RecordOutcome::Updated(key) =>
+                    if is_written && ! self.used_keys[sourcei].insert(key.clone()) {
+                        bail!("{:?} {key:?} used multiple times",
+                              sources[sourcei].mapping.tsv_key)
+                    }
+                RecordOutcome::Missing(_, _) =>
+                    self.num_missing[sourcei] += 1,
+            }
+        }
+        Ok(())
+    }
+
+    /// The number of TSV rows of source `sourcei` not used by any
+    /// written record.
+    pub fn num_unused(&self, sources: &[Source], sourcei: usize) -> usize {
+        sources[sourcei].tsv.rows.len() - self.used_keys[sourcei].len()
+    }
+
+    /// Write the keys of the unused rows, one per line, prefixed with
+    /// the TSV path and a tab if there are several sources.
+    pub fn write_unused_keys<W: Write>(&self, sources: &[Source], mut outp: W) -> Result<()> {
+        for (source, used_keys) in sources.iter().zip(&self.used_keys) {
+            for key in source.unused_keys(used_keys) {
+                if sources.len() > 1 {
+                    write!(&mut outp, "{}\t", source.tsv.path)?;
+                }
+                writeln!(&mut outp, "{key}")?;
+            }
+        }
+        outp.flush()?;
+        Ok(())
+    }
+
+    /// Apply `policy` to the unused rows of all sources, writing
+    /// `UnusedRowPolicy::Report` reports to `outp`.
+    pub fn check_unused<W: Write>(
+        &self, sources: &[Source], policy: UnusedRowPolicy, mut outp: W
+    ) -> Result<()> {
+        for (sourcei, (source, used_keys)) in sources.iter().zip(&self.used_keys).enumerate() {
+            let tsvpath = &source.tsv.path;
+            let num_unused = self.num_unused(sources, sourcei);
+            if num_unused == 0 {
+                continue
+            }
+            match policy {
+                UnusedRowPolicy::Error =>
+                    bail!("{num_unused} rows in {tsvpath:?} matched no record, e.g. {:?}",
+                          source.unused_keys(used_keys).next().expect("num_unused > 0")),
+                UnusedRowPolicy::Report => {
+                    writeln!(&mut outp, "{num_unused} rows in {tsvpath:?} matched no record:")?;
+                    for key in source.unused_keys(used_keys).take(REPORT_MAX_KEYS) {
+                        writeln!(&mut outp, "  {key}")?;
+                    }
+                    if num_unused > REPORT_MAX_KEYS {
+                        writeln!(&mut outp, "  ...")?;
+                    }
+                }
+                UnusedRowPolicy::Ignore => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+
+/// How many examples `ChangeStats` keeps per field.
+const CHANGE_SAMPLES: usize = 5;
+
+#[derive(Debug, Default)]
+struct FieldStats {
+    new: u64,
+    same: u64,
+    changed: u64,
+    /// (key, old, new)
+    samples: Vec<(String, JsonValue, JsonValue)>,
+}
+
+/// Counts of the kinds of changes per mapped field, with a few
+/// examples of changed values.
+#[derive(Debug)]
+pub struct ChangeStats {
+    /// Per source, per mapped column
+    fields: Vec<Vec<FieldStats>>,
+}
+
+impl ChangeStats {
+    pub fn new(sources: &[Source]) -> Self {
+        ChangeStats {
+            fields: sources.iter().map(
+                |s| s.mapping.columns.iter().map(|_| Default::default()).collect())
+                .collect()
+        }
+    }
+
+    pub fn add(&mut self, update: &RecordUpdate) {
+        for change in &update.changes {
+            let stats = &mut self.fields[change.source][change.column];
+            match change.kind() {
+                ChangeKind::New => stats.new += 1,
+                ChangeKind::Same => stats.same += 1,
+                ChangeKind::Changed => {
+                    stats.changed += 1;
+                    if stats.samples.len() < CHANGE_SAMPLES {
+                        stats.samples.push((
+                            update.outcomes[change.source].key().into(),
+                            change.old.clone().expect("changed means present"),
+                            change.new.clone()));
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn print<W: Write>(&self, sources: &[Source], mut outp: W) -> Result<()> {
+        for (source, fields) in sources.iter().zip(&self.fields) {
+            let mapping = &source.mapping;
+            for (m, stats) in mapping.columns.iter().zip(fields) {
+                writeln!(&mut outp, "{} (from {:?}): {} new, {} same, {} changed",
+                         mapping.target.join(&m.field), source.tsv.path,
+                         stats.new, stats.same, stats.changed)?;
+                for (key, old, new) in &stats.samples {
+                    writeln!(&mut outp, "  {key}: {} -> {}", old.dump(), new.dump())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use csv::StringRecord;
+
+    use super::*;
+    use crate::column_mapping::{ColumnMapping, ColumnType};
+
+    fn tsv(path: &str, rows: &[&[&str]]) -> Tsv {
+        Tsv {
+            path: path.into(),
+            headers: StringRecord::from(rows[0].to_vec()),
+            rows: rows[1..].iter().map(|r| StringRecord::from(r.to_vec())).collect(),
+        }
+    }
+
+    fn mapping(column: &str) -> MappingConfig {
+        let mut mapping = MappingConfig::with_columns(vec![ColumnMapping {
+            column: column.into(),
+            field: "lin".into(),
+            column_type: ColumnType::String,
+        }]);
+        mapping.tsv_key = "id".into();
+        mapping
+    }
+
+    #[test]
+    fn t_conflicts() {
+        let a = tsv("a.tsv", &[&["id", "lin"], &["E1", "BA.2"], &["E2", "B.1"]]);
+        let b = tsv("b.tsv", &[&["id", "pango"], &["E1", "BA.3"], &["E2", "B.1"]]);
+        let updater = |conflicts| Updater::new(
+            vec![Source::new(&a, mapping("lin")).unwrap(),
+                 Source::new(&b, mapping("pango")).unwrap()],
+            MissingRowPolicy::Null, conflicts).unwrap();
+        let lin = |updater: &Updater, id: &str| {
+            let mut entry = jzon::object! { metadata: { gisaid_epi_isl: id } };
+            updater.update_record(&mut entry).map(|_| entry["metadata"]["lin"].dump())
+        };
+        assert!(lin(&updater(ConflictPolicy::Error), "E1").is_err());
+        assert_eq!(lin(&updater(ConflictPolicy::Error), "E2").unwrap(), "\"B.1\"");
+        assert_eq!(lin(&updater(ConflictPolicy::First), "E1").unwrap(), "\"BA.2\"");
+        assert_eq!(lin(&updater(ConflictPolicy::Last), "E1").unwrap(), "\"BA.3\"");
+        // A missing row in the last source doesn't override with null
+        let c = tsv("c.tsv", &[&["id", "lin"]]);
+        let updater = Updater::new(
+            vec![Source::new(&a, mapping("lin")).unwrap(),
+                 Source::new(&c, mapping("lin")).unwrap()],
+            MissingRowPolicy::Null, ConflictPolicy::Last).unwrap();
+        assert_eq!(lin(&updater, "E1").unwrap(), "\"BA.2\"");
+        assert_eq!(updater.update_line(r#"{"metadata":{"gisaid_epi_isl":"E1"}}"#).unwrap().1.unwrap(),
+                   r#"{"metadata":{"gisaid_epi_isl":"E1","lin":"BA.2"}}"#);
+    }
+
+    #[test]
+    fn t_row_usage() {
+        let a = tsv("a.tsv", &[&["id", "lin"], &["E1", "BA.2"], &["E2", "B.1"], &["E3", "B.2"]]);
+        let b = tsv("b.tsv", &[&["id", "pango"], &["E1", "BA.2"], &["E3", "B.2"], &["E4", "A"]]);
+        let updater = |missing_row| Updater::new(
+            vec![Source::new(&a, mapping("lin")).unwrap(),
+                 Source::new(&b, mapping("pango")).unwrap()],
+            missing_row, ConflictPolicy::Error).unwrap();
+        let run = |updater: &Updater, ids: &[&str]| -> Result<(RowUsage, Vec<String>)> {
+            let mut usage = RowUsage::new(updater.sources());
+            let mut written = Vec::new();
+            for id in ids {
+                let line = format!(r#"{{"metadata":{{"gisaid_epi_isl":"{id}"}}}}"#);
+                let (update, output) = updater.update_line(&line)?;
+                usage.add(updater.sources(), &update)?;
+                if output.is_some() {
+                    written.push(id.to_string());
+                }
+            }
+            Ok((usage, written))
+        };
+        let unused = |updater: &Updater, usage: &RowUsage, sourcei: usize| {
+            updater.sources()[sourcei].unused_keys(&usage.used_keys[sourcei])
+                .map(String::from).collect::<Vec<_>>()
+        };
+
+        // E2 is missing in b.tsv, the record is skipped, so the row
+        // of a.tsv counts as unused
+        let updater_skip = updater(MissingRowPolicy::Skip);
+        let (usage, written) = run(&updater_skip, &["E1", "E2", "E3"]).unwrap();
+        assert_eq!(written, ["E1", "E3"]);
+        assert_eq!(usage.num_missing, [0, 1]);
+        assert_eq!(usage.num_unused(updater_skip.sources(), 0), 1);
+        assert_eq!(unused(&updater_skip, &usage, 0), ["E2"]);
+        assert_eq!(unused(&updater_skip, &usage, 1), ["E4"]);
+        // Missing in the first source, the second isn't looked at
+        let (usage, written) = run(&updater_skip, &["E4"]).unwrap();
+        assert!(written.is_empty());
+        assert_eq!(usage.num_missing, [1, 0]);
+        assert_eq!(unused(&updater_skip, &usage, 1), ["E1", "E3", "E4"]);
+        // A skipped record doesn't count for duplicate use
+        assert!(run(&updater_skip, &["E2", "E2", "E1"]).is_ok());
+        assert!(run(&updater_skip, &["E1", "E3", "E1"]).is_err());
+
+        let updater_pass = updater(MissingRowPolicy::Pass);
+        let (usage, written) = run(&updater_pass, &["E1", "E2", "E3"]).unwrap();
+        assert_eq!(written, ["E1", "E2", "E3"]);
+        assert_eq!(usage.num_missing, [0, 1]);
+        assert!(unused(&updater_pass, &usage, 0).is_empty());
+        assert_eq!(usage.num_unused(updater_pass.sources(), 1), 1);
+    }
+
+    #[test]
+    fn t_missing_row_policies() {
+        let a = tsv("a.tsv", &[&["id", "lin"], &["E1", "BA.2"]]);
+        let updater = |missing_row| Updater::new(
+            vec![Source::new(&a, mapping("lin")).unwrap()],
+            missing_row, ConflictPolicy::Error).unwrap();
+        let line = r#"{"metadata":{"gisaid_epi_isl":"E2","lin":"B.1"}}"#;
+        let update = |missing_row| updater(missing_row).update_line(line);
+
+        let e = update(MissingRowPolicy::Error).unwrap_err();
+        assert_eq!(e.to_string(), r#"unknown "id" value "E2" in "a.tsv""#);
+        let (u, output) = update(MissingRowPolicy::Skip).unwrap();
+        assert_eq!(u.outcomes, [RecordOutcome::Missing("E2".into(), MissingRowPolicy::Skip)]);
+        assert!(! u.is_written());
+        assert_eq!(output, None);
+        let (u, output) = update(MissingRowPolicy::Pass).unwrap();
+        assert!(u.is_written());
+        assert_eq!(output.unwrap(), line);
+        let (u, output) = update(MissingRowPolicy::Null).unwrap();
+        assert_eq!(u.outcomes, [RecordOutcome::Missing("E2".into(), MissingRowPolicy::Null)]);
+        assert_eq!(output.unwrap(), r#"{"metadata":{"gisaid_epi_isl":"E2","lin":null}}"#);
+
+        // The same with parsed records
+        let mut entry = jzon::parse(line).unwrap();
+        assert!(updater(MissingRowPolicy::Error).update_record(&mut entry).is_err());
+        let u = updater(MissingRowPolicy::Skip).update_record(&mut entry).unwrap();
+        assert!(! u.is_written());
+        updater(MissingRowPolicy::Pass).update_record(&mut entry).unwrap();
+        assert_eq!(entry.dump(), line);
+        updater(MissingRowPolicy::Null).update_record(&mut entry).unwrap();
+        assert!(entry["metadata"]["lin"].is_null());
+        // Matching rows are unaffected
+        let (u, output) = updater(MissingRowPolicy::Skip).update_line(
+            r#"{"metadata":{"gisaid_epi_isl":"E1"}}"#).unwrap();
+        assert_eq!(u.outcomes, [RecordOutcome::Updated("E1".into())]);
+        assert_eq!(output.unwrap(), r#"{"metadata":{"gisaid_epi_isl":"E1","lin":"BA.2"}}"#);
+
+        assert_eq!("null".parse::<MissingRowPolicy>().unwrap(), MissingRowPolicy::Null);
+        assert!("none".parse::<MissingRowPolicy>().is_err());
+    }
+
+    #[test]
+    fn t_unused_rows() {
+        let mut rows: Vec<Vec<String>> = vec![vec!["id".into(), "lin".into()]];
+        for i in 0..REPORT_MAX_KEYS + 2 {
+            rows.push(vec![format!("E{i}"), "B.1".into()]);
+        }
+        let rows: Vec<Vec<&str>> = rows.iter().map(
+            |r| r.iter().map(|c| c.as_str()).collect()).collect();
+        let rows: Vec<&[&str]> = rows.iter().map(|r| &r[..]).collect();
+        let a = tsv("a.tsv", &rows);
+        let b = tsv("b.tsv", &[&["id", "pango"], &["E0", "B.1"], &["E1", "B.1"]]);
+        let updater = Updater::new(
+            vec![Source::new(&a, mapping("lin")).unwrap(),
+                 Source::new(&b, mapping("pango")).unwrap()],
+            MissingRowPolicy::Pass, ConflictPolicy::Error).unwrap();
+        let sources = updater.sources();
+        let mut usage = RowUsage::new(sources);
+        for id in ["E0", "E1"] {
+            let mut entry = jzon::object! { metadata: { gisaid_epi_isl: id } };
+            usage.add(sources, &updater.update_record(&mut entry).unwrap()).unwrap();
+        }
+        assert_eq!(usage.num_unused(sources, 0), REPORT_MAX_KEYS);
+        assert_eq!(usage.num_unused(sources, 1), 0);
+
+        let report = |policy| -> Result<String> {
+            let mut out = Vec::new();
+            usage.check_unused(sources, policy, &mut out)?;
+            Ok(String::from_utf8(out).unwrap())
+        };
+        assert_eq!(report(UnusedRowPolicy::Error).unwrap_err().to_string(),
+                   r#"20 rows in "a.tsv" matched no record, e.g. "E2""#);
+        assert_eq!(report(UnusedRowPolicy::Ignore).unwrap(), "");
+        let r = report(UnusedRowPolicy::Report).unwrap();
+        let lines: Vec<&str> = r.lines().collect();
+        assert_eq!(lines[0], r#"20 rows in "a.tsv" matched no record:"#);
+        assert_eq!(lines[1], "  E2");
+        assert_eq!(lines.len(), 1 + REPORT_MAX_KEYS);
+
+        // Beyond REPORT_MAX_KEYS, the report is cut off
+        let mut entry = jzon::object! { metadata: { gisaid_epi_isl: "E2" } };
+        let mut usage = RowUsage::new(sources);
+        usage.add(sources, &updater.update_record(&mut entry).unwrap()).unwrap();
+        let mut out = Vec::new();
+        usage.check_unused(sources, UnusedRowPolicy::Report, &mut out).unwrap();
+        let r = String::from_utf8(out).unwrap();
+        assert!(r.starts_with("21 rows in \"a.tsv\" matched no record:\n  E0\n  E1\n  E3\n"));
+        assert!(r.ends_with("  E20\n  ...\n2 rows in \"b.tsv\" matched no record:\n  E0\n  E1\n"));
+
+        let mut out = Vec::new();
+        usage.write_unused_keys(sources, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("a.tsv\tE0\na.tsv\tE1\na.tsv\tE3\n"));
+        assert!(out.ends_with("a.tsv\tE21\nb.tsv\tE0\nb.tsv\tE1\n"));
+        // Without path with a single source
+        let updater = Updater::new(vec![Source::new(&b, mapping("pango")).unwrap()],
+                                   MissingRowPolicy::Skip, ConflictPolicy::Error).unwrap();
+        let mut out = Vec::new();
+        RowUsage::new(updater.sources()).write_unused_keys(updater.sources(), &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "E0\nE1\n");
+
+        assert_eq!("report".parse::<UnusedRowPolicy>().unwrap(), UnusedRowPolicy::Report);
+        assert!("warn".parse::<UnusedRowPolicy>().is_err());
+    }
+
+    #[test]
+    fn t_changes() {
+        let change = |old: Option<JsonValue>, new: JsonValue| FieldChange {
+            source: 0, column: 0, old, new
+        };
+        assert_eq!(change(None, "B.1".into()).kind(), ChangeKind::New);
+        assert_eq!(change(None, JsonValue::Null).kind(), ChangeKind::New);
+        assert_eq!(change(Some("B.1".into()), "B.1".into()).kind(), ChangeKind::Same);
+        assert_eq!(change(Some(JsonValue::Null), JsonValue::Null).kind(), ChangeKind::Same);
+        assert_eq!(change(Some("B.1".into()), "B.2".into()).kind(), ChangeKind::Changed);
+        assert_eq!(change(Some(1.into()), "1".into()).kind(), ChangeKind::Changed);
+
+        let a = tsv("a.tsv", &[&["id", "lin"], &["E1", "BA.2"], &["E2", "B.1"], &["E3", "B.1"]]);
+        let mut updater = Updater::new(
+            vec![Source::new(&a, mapping("lin")).unwrap()],
+            MissingRowPolicy::Pass, ConflictPolicy::Error).unwrap();
+        updater.set_track_changes(true);
+        let sources = updater.sources();
+        let mut stats = ChangeStats::new(sources);
+        let mut diff = Vec::new();
+        for line in [
+            r#"{"metadata":{"gisaid_epi_isl":"E1","lin":"BA.1"}}"#,
+            r#"{"metadata":{"gisaid_epi_isl":"E2","lin":"B.1"}}"#,
+            r#"{"metadata":{"gisaid_epi_isl":"E3"}}"#,
+            r#"{"metadata":{"gisaid_epi_isl":"E4"}}"#,
+        ] {
+            let update = updater.check_line(line).unwrap();
+            // The same as when updating
+            assert_eq!(update, updater.update_line(line).unwrap().0);
+            stats.add(&update);
+            update.write_diff(sources, &mut diff).unwrap();
+        }
+        assert_eq!(String::from_utf8(diff).unwrap(),
+                   "{\"key\":\"E1\",\"field\":\"metadata.lin\",\"old\":\"BA.1\",\"new\":\"BA.2\"}\n\
+                    {\"key\":\"E3\",\"field\":\"metadata.lin\",\"new\":\"B.1\"}\n");
+        let mut out = Vec::new();
+        stats.print(sources, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "metadata.lin (from \"a.tsv\"): 1 new, 1 same, 1 changed\n  \
+                    E1: \"BA.1\" -> \"BA.2\"\n");
+
+        // Without tracking, there are no changes
+        updater.set_track_changes(false);
+        assert!(updater.check_line(r#"{"metadata":{"gisaid_epi_isl":"E1"}}"#)
+                .unwrap().changes.is_empty());
+    }
+}
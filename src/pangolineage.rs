@@ -1,45 +1,118 @@
 //! Pango lineage parsing
 
-use std::convert::TryFrom;
+use std::{convert::{TryFrom, TryInto}, marker::PhantomData};
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
 use kstring::KString;
 use lazy_static::lazy_static;
 use regex::Regex;
 
 
 pub trait BaseName {
-    fn new(basename: KString) -> Result<Self> where Self: Sized;
     fn as_str(&self) -> &str;
     fn as_kstring(&self) -> &KString;
-    fn to_kstring(&self) -> KString;
+    fn to_kstring(&self) -> KString {
+        self.as_kstring().clone()
+    }
     fn into_kstring(self) -> KString;
 }
 
 
 lazy_static!{
     static ref VALID_BASENAME: Regex = Regex::new(r"^[A-Z]{1,4}\z").unwrap();
+    static ref MIXED_CASE_BASENAME: Regex = Regex::new(r"^[A-Za-z]{1,4}\z").unwrap();
+    /// Separates the excluded patterns in a `LineageQuery`
+    static ref EXCEPT: Regex = Regex::new(r"(?:^|\s)except(?:\s|$)").unwrap();
+}
+
+
+/// Why a string is not a valid lineage name.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum LineageParseError {
+    #[error("empty lineage name")]
+    Empty,
+    #[error("invalid base name {0:?}, expecting 1 to 4 letters A-Z")]
+    InvalidBaseName(String),
+    /// Accepted when normalizing case (see `PangoLineage::parse`)
+    #[error("lowercase letters in base name {0:?}")]
+    Lowercase(String),
+    #[error("empty path component in lineage {0:?}")]
+    EmptyComponent(String),
+    #[error("non-numeric path component {component:?} in lineage {lineage:?}")]
+    NonNumericComponent { lineage: String, component: String },
+    #[error("path component {component:?} in lineage {lineage:?} is too large")]
+    Overflow { lineage: String, component: String },
 }
 
 
+/// What is known about a base name, as a type parameter of
+/// `GenericBaseName`. Adding a state only takes a marker type and an
+/// impl of this trait.
+pub trait BaseNameState: std::fmt::Debug + Clone + PartialEq + Eq + std::hash::Hash
+    + PartialOrd + Ord
+{
+    /// For error messages
+    const TYPE_NAME: &'static str;
+}
+
 /// Could be either an original haplotype, or an alias
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct UndeterminedBaseName(KString);
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Undetermined;
 
-impl UndeterminedBaseName {
-    pub fn into_haplo_type_base_name(self) -> HaplotypeBasename {
-        HaplotypeBasename(self.0)
-    }
+impl BaseNameState for Undetermined {
+    const TYPE_NAME: &'static str = "UndeterminedBaseName";
 }
 
-impl BaseName for UndeterminedBaseName {
-    fn new(basename: KString) -> Result<Self> {
+/// An original haplotype (or recombinant) name, i.e. the root of a
+/// canonical lineage path
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Haplotype;
+
+impl BaseNameState for Haplotype {
+    const TYPE_NAME: &'static str = "HaplotypeBasename";
+}
+
+/// Known to be an alias for a path (see `LineageAliases`)
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Alias;
+
+impl BaseNameState for Alias {
+    const TYPE_NAME: &'static str = "AliasBaseName";
+}
+
+/// A base name (the part before the first dot of a lineage name),
+/// with what is known about it in the type.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct GenericBaseName<S: BaseNameState>(KString, PhantomData<S>);
+
+pub type UndeterminedBaseName = GenericBaseName<Undetermined>;
+pub type HaplotypeBasename = GenericBaseName<Haplotype>;
+pub type AliasBaseName = GenericBaseName<Alias>;
+
+impl<S: BaseNameState> GenericBaseName<S> {
+    pub fn new(basename: KString) -> Result<Self, LineageParseError> {
         if ! VALID_BASENAME.is_match(&basename) {
-            bail!("invalid string for UndeterminedBaseName {:?}", &*basename)
+            if MIXED_CASE_BASENAME.is_match(&basename) {
+                return Err(LineageParseError::Lowercase(basename.as_str().into()))
+            }
+            return Err(LineageParseError::InvalidBaseName(basename.as_str().into()))
         }
-        Ok(Self(basename))
+        Ok(Self(basename, PhantomData))
+    }
+
+    /// Change the state without checks; private since the state
+    /// is supposed to reflect what is known.
+    fn into_state<T: BaseNameState>(self) -> GenericBaseName<T> {
+        GenericBaseName(self.0, PhantomData)
+    }
+
+    /// Forget what is known about the name; always safe.
+    pub fn into_undetermined(self) -> UndeterminedBaseName {
+        self.into_state()
     }
+}
 
+impl<S: BaseNameState> BaseName for GenericBaseName<S> {
     fn as_str(&self) -> &str {
         self.0.as_str()
     }
@@ -48,62 +121,76 @@ impl BaseName for UndeterminedBaseName {
         &self.0
     }
 
-    fn to_kstring(&self) -> KString {
-        self.0.clone()
-    }
-
     fn into_kstring(self) -> KString {
         self.0
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct HaplotypeBasename(KString);
+impl<S: BaseNameState> std::fmt::Display for GenericBaseName<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
 
-// stupid copy-paste!
-impl BaseName for HaplotypeBasename {
-    fn new(basename: KString) -> Result<Self> {
-        if ! VALID_BASENAME.is_match(&basename) {
-            bail!("invalid string for HaplotypeBasename: {:?}", &*basename)
+impl UndeterminedBaseName {
+    /// Assume that the name is an original haplotype name; see
+    /// `LineageAliases::determine` for checking.
+    pub fn into_haplo_type_base_name(self) -> HaplotypeBasename {
+        self.into_state()
+    }
+
+    /// Mark as original haplotype or alias, according to
+    /// `is_alias`.
+    pub fn into_determined(self, is_alias: bool) -> DeterminedBaseName {
+        if is_alias {
+            DeterminedBaseName::Alias(self.into_state())
+        } else {
+            DeterminedBaseName::OriginalHaplotype(self.into_state())
         }
-        Ok(Self(basename))
     }
+}
 
-    fn as_str(&self) -> &str {
-        self.0.as_str()
+
+/// Known whether it's an alias or not; see `LineageAliases::determine`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum DeterminedBaseName {
+    OriginalHaplotype(HaplotypeBasename),
+    Alias(AliasBaseName),
+}
+
+impl DeterminedBaseName {
+    pub fn is_alias(&self) -> bool {
+        matches!(self, DeterminedBaseName::Alias(_))
     }
+}
 
-    fn as_kstring(&self) -> &KString {
-        &self.0
+impl BaseName for DeterminedBaseName {
+    fn as_str(&self) -> &str {
+        match self {
+            DeterminedBaseName::OriginalHaplotype(s) => s.as_str(),
+            DeterminedBaseName::Alias(s) => s.as_str()
+        }
     }
 
-    fn to_kstring(&self) -> KString {
-        self.0.clone()
+    fn as_kstring(&self) -> &KString {
+        match self {
+            DeterminedBaseName::OriginalHaplotype(s) => s.as_kstring(),
+            DeterminedBaseName::Alias(s) => s.as_kstring()
+        }
     }
 
     fn into_kstring(self) -> KString {
-        self.0
+        match self {
+            DeterminedBaseName::OriginalHaplotype(s) => s.into_kstring(),
+            DeterminedBaseName::Alias(s) => s.into_kstring()
+        }
     }
 }
- 
 
-// /// Known when it's an alias or not
-// pub enum DeterminedBaseName {
-//     OriginalHaplotype(KString),
-//     Alias(KString),
-// }
 
-// impl BaseName for DeterminedBaseName {
-//     fn as_str(&self) -> &str {
-//         match self {
-//             DeterminedBaseName::OriginalHaplotype(s) => s.as_str(),
-//             DeterminedBaseName::Alias(s) => s.as_str()
-//         }
-//     }
-// }
-
-
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// Compares numerically, element by element (`1.2` < `1.10`), and
+/// ancestors before their sublineages.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct Subpath(Vec<u16>);
 
 impl Subpath {
@@ -119,7 +206,7 @@ impl Subpath {
 
     pub fn is_ancestor_of(&self, possible_other: &Self, include_self: bool) -> bool {
         let selflen = self.0.len();
-        if selflen <= possible_other.0.len() && self.0 == &possible_other.0[0..selflen] {
+        if selflen <= possible_other.0.len() && self.0 == possible_other.0[0..selflen] {
             // prefix is the same
             if include_self {
                 true
@@ -133,6 +220,7 @@ impl Subpath {
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests1 {
     use super::*;
 
@@ -179,7 +267,8 @@ impl std::convert::AsRef<[u16]> for Subpath {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+/// Ordered by base name, then path (see `Subpath`).
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct PangoLineage<B: BaseName>(pub B, pub Subpath);
 
 impl<B: BaseName> PangoLineage<B> {
@@ -187,14 +276,31 @@ impl<B: BaseName> PangoLineage<B> {
         Self(basename, subpath)
     }
 
-    pub fn to_string(&self) -> String {
-        let mut s = String::new();
-        s.push_str(self.0.as_str());
+    /// The lineage one level up on the path, None for a base name
+    /// without path.
+    pub fn parent(&self) -> Option<Self> where B: Clone {
+        let path = self.1.as_ref();
+        if path.is_empty() {
+            None
+        } else {
+            Some(Self(self.0.clone(), Subpath(path[..path.len() - 1].into())))
+        }
+    }
+}
+
+impl<B: BaseName> std::fmt::Display for PangoLineage<B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.0.as_str())?;
         for sublevel in self.1.as_ref() {
-            s.push('.');
-            s.push_str(&sublevel.to_string()); // XX optim
+            write!(f, ".{sublevel}")?;
         }
-        s
+        Ok(())
+    }
+}
+
+impl<B: BaseName> serde::Serialize for PangoLineage<B> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
     }
 }
 
@@ -207,16 +313,106 @@ impl<B: BaseName> PangoLineage<B> {
 /// e.g. `is_ancestor_of` checks (omitting the necessary
 /// `canonicalize` call).
 impl TryFrom<&str> for PangoLineage<UndeterminedBaseName> {
-    type Error = anyhow::Error;
+    type Error = LineageParseError;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Self::parse(value, false)
+    }
+}
+
+impl PangoLineage<UndeterminedBaseName> {
+    /// Parse a lineage name; with `normalize_case`, lowercase letters
+    /// in the base name are accepted and uppercased (`ba.2` ->
+    /// `BA.2`).
+    pub fn parse(value: &str, normalize_case: bool) -> Result<Self, LineageParseError> {
+        if value.is_empty() {
+            return Err(LineageParseError::Empty)
+        }
         let mut parts = value.split('.');
         let part0 = parts.next().expect("split returns at least 1 element");
-        if part0.is_empty() {
-            bail!("empty string can't be a base name")
+        let basename = if normalize_case && MIXED_CASE_BASENAME.is_match(part0) {
+            KString::from_string(part0.to_ascii_uppercase())
+        } else {
+            KString::from_ref(part0)
+        };
+        let basename = UndeterminedBaseName::new(basename)?;
+        let subpath = parts.map(|component| {
+            if component.is_empty() {
+                return Err(LineageParseError::EmptyComponent(value.into()))
+            }
+            if ! component.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(LineageParseError::NonNumericComponent {
+                    lineage: value.into(), component: component.into()
+                })
+            }
+            component.parse().map_err(|_| LineageParseError::Overflow {
+                lineage: value.into(), component: component.into()
+            })
+        }).collect::<Result<Vec<_>, _>>()?;
+        Ok(Self(basename, Subpath(subpath)))
+    }
+}
+
+/// A lineage name as found in data, where withdrawn (recalled)
+/// lineages are marked with a `*` prefix, e.g. `*J.1`. The path is
+/// kept as it was; see `LineageAliases::canonicalize_name` for
+/// finding a current lineage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineageName {
+    pub withdrawn: bool,
+    pub lineage: PangoLineage<UndeterminedBaseName>,
+}
+
+impl TryFrom<&str> for LineageName {
+    type Error = LineageParseError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Self::parse(value, false)
+    }
+}
+
+impl LineageName {
+    /// Like `PangoLineage::parse`, with an optional `*` prefix.
+    pub fn parse(value: &str, normalize_case: bool) -> Result<Self, LineageParseError> {
+        let (withdrawn, rest) = match value.strip_prefix('*') {
+            Some(rest) => (true, rest),
+            None => (false, value)
+        };
+        Ok(Self { withdrawn, lineage: PangoLineage::parse(rest, normalize_case)? })
+    }
+}
+
+impl std::fmt::Display for LineageName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.withdrawn {
+            f.write_str("*")?;
         }
-        Ok(Self(UndeterminedBaseName::new(KString::from_ref(part0))?,
-                Subpath(parts.map(|s| Ok(s.parse()?)).collect::<Result<Vec<_>>>()?)))
+        write!(f, "{}", self.lineage)
+    }
+}
+
+impl std::str::FromStr for PangoLineage<UndeterminedBaseName> {
+    type Err = LineageParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::try_from(s)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for PangoLineage<UndeterminedBaseName> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = <std::borrow::Cow<str>>::deserialize(deserializer)?;
+        Self::try_from(&*s).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Expects the canonical form (as serialized from a
+/// `PangoLineage<HaplotypeBasename>`), the same caveat as for
+/// `force_into_canonicalization` applies.
+impl<'de> serde::Deserialize<'de> for PangoLineage<HaplotypeBasename> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(PangoLineage::<UndeterminedBaseName>::deserialize(deserializer)?
+           .force_into_canonicalization())
     }
 }
 
@@ -237,6 +433,104 @@ impl PangoLineage<UndeterminedBaseName> {
     }
 }
 
+/// One element of a `LineageQuery`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LineagePattern {
+    /// `BA.2`: just this lineage
+    Exact(PangoLineage<UndeterminedBaseName>),
+    /// `BA.2*` or `BA.2+`: the lineage and all its sublineages
+    WithSublineages(PangoLineage<UndeterminedBaseName>),
+}
+
+impl TryFrom<&str> for LineagePattern {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Ok(match value.strip_suffix(|c| c == '*' || c == '+') {
+            Some(lin) => LineagePattern::WithSublineages(lin.try_into()?),
+            None => LineagePattern::Exact(value.try_into()?),
+        })
+    }
+}
+
+impl std::fmt::Display for LineagePattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LineagePattern::Exact(l) => write!(f, "{l}"),
+            LineagePattern::WithSublineages(l) => write!(f, "{l}*"),
+        }
+    }
+}
+
+/// A filter on lineages, e.g. `BA.2*, XBB* except XBB.1.5*`:
+/// patterns joined with `,` or `or` (union), optionally followed by
+/// `except` and patterns that are excluded. See
+/// `LineageAliases::compile_query` for matching lineages against it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineageQuery {
+    pub include: Vec<LineagePattern>,
+    pub exclude: Vec<LineagePattern>,
+}
+
+impl TryFrom<&str> for LineageQuery {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let parse_union = |s: &str| -> Result<Vec<LineagePattern>> {
+            let mut patterns = Vec::new();
+            for part in s.split(',') {
+                let mut words = part.split_whitespace();
+                loop {
+                    let word = match words.next() {
+                        Some(word) => word,
+                        None => bail!("missing lineage in query {value:?}")
+                    };
+                    patterns.push(LineagePattern::try_from(word).map_err(
+                        |e| anyhow!("invalid lineage pattern {word:?} in query {value:?}: {e}"))?);
+                    match words.next() {
+                        None => break,
+                        Some("or") => (),
+                        Some(word) => bail!("expecting ',', 'or' or 'except' instead of \
+                                             {word:?} in query {value:?}")
+                    }
+                }
+            }
+            Ok(patterns)
+        };
+        let mut parts = EXCEPT.split(value);
+        let include = parse_union(parts.next().expect("split returns at least 1 element"))?;
+        let exclude = match parts.next() {
+            Some(s) => parse_union(s)?,
+            None => Vec::new(),
+        };
+        if parts.next().is_some() {
+            bail!("multiple 'except' in query {value:?}")
+        }
+        Ok(Self { include, exclude })
+    }
+}
+
+impl std::fmt::Display for LineageQuery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let write_union = |f: &mut std::fmt::Formatter<'_>, patterns: &[LineagePattern]| {
+            for (i, p) in patterns.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(", ")?;
+                }
+                write!(f, "{p}")?;
+            }
+            Ok(())
+        };
+        write_union(f, &self.include)?;
+        if ! self.exclude.is_empty() {
+            f.write_str(" except ")?;
+            write_union(f, &self.exclude)?;
+        }
+        Ok(())
+    }
+}
+
+
 #[cfg(test)]
 mod tests2 {
     use std::convert::TryInto;
@@ -256,6 +550,23 @@ mod tests2 {
         assert_eq!(l.1.as_ref(), &[7u16, 1, 2]);
     }
 
+    #[test]
+    fn t_withdrawn() {
+        let l : LineageName = "*J.1".try_into().unwrap();
+        assert!(l.withdrawn);
+        assert_eq!(l.lineage.0.as_str(), "J");
+        assert_eq!(l.lineage.1.as_ref(), &[1u16]);
+        assert_eq!(l.to_string(), "*J.1");
+        assert_eq!(l.lineage.parent().unwrap().to_string(), "J");
+        assert_eq!(l.lineage.parent().unwrap().parent(), None);
+
+        let l : LineageName = "B.1".try_into().unwrap();
+        assert!(! l.withdrawn);
+        assert_eq!(l.to_string(), "B.1");
+        assert!(LineageName::try_from("**J.1").is_err());
+        assert!(LineageName::try_from("*").is_err());
+    }
+
     #[test]
     fn t_ancestry() {
         let lineages =
@@ -267,7 +578,7 @@ mod tests2 {
             .map(|(a, b)| {
                 (a.to_string(),
                  b.to_string(),
-                 a.is_ancestor_of(&b, true))
+                 a.is_ancestor_of(b, true))
             }).zip(&[
                 ("A.1", "A.1", true),
                 ("A.1", "B", false),
@@ -305,3 +616,107 @@ mod tests2 {
         }
     }
 }
+
+#[cfg(test)]
+mod tests_query {
+    use super::*;
+
+    #[test]
+    fn t_query_parse() {
+        let q = |s: &str| LineageQuery::try_from(s).map(|q| q.to_string());
+        assert_eq!(q("BA.2").unwrap(), "BA.2");
+        assert_eq!(q("BA.2.75+").unwrap(), "BA.2.75*");
+        assert_eq!(q("BA.2*,BA.1 or  B.1.1.7").unwrap(), "BA.2*, BA.1, B.1.1.7");
+        assert_eq!(q("XBB* except XBB.1.5*, EG.5").unwrap(), "XBB* except XBB.1.5*, EG.5");
+        assert!(q("").is_err());
+        assert!(q("BA.2,").is_err());
+        assert!(q("BA.2 BA.1").is_err());
+        assert!(q("BA.2**").is_err());
+        assert!(q("BA.x").is_err());
+        assert!(q("A except B except C").is_err());
+        assert_eq!(q("XBB*\texcept  XBB.1.5*,EG.5").unwrap(), "XBB* except XBB.1.5*, EG.5");
+        assert_eq!(q("XBB*\nexcept\tEG.5").unwrap(), "XBB* except EG.5");
+        assert!(q("XBB* except").is_err());
+        assert!(q("except XBB*").is_err());
+        assert!(q("XBB* exceptEG.5").is_err());
+    }
+}
+
+#[cfg(test)]
+mod tests_display_fromstr {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn lin(s: &str) -> PangoLineage<UndeterminedBaseName> {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn t_ord() {
+        let mut ls: Vec<_> = ["B.1.10", "BA.1", "B.1.2", "B", "A.2", "B.1", "B.1.2.1", "B.2"]
+            .iter().map(|s| lin(s)).collect();
+        ls.sort();
+        assert_eq!(ls.iter().map(|l| l.to_string()).collect::<Vec<_>>(),
+                   ["A.2", "B", "B.1", "B.1.2", "B.1.2.1", "B.1.10", "B.2", "BA.1"]);
+    }
+
+    #[test]
+    fn t_hash() {
+        let set: HashSet<_> = ["B.1", "B.1.0", "B.1"].iter().map(|s| lin(s)).collect();
+        assert_eq!(set.len(), 2);
+        assert!(set.contains(&lin("B.1.0")));
+    }
+
+    #[test]
+    fn t_serde() {
+        let l = lin("BA.2.75");
+        assert_eq!(serde_json::to_string(&l).unwrap(), r#""BA.2.75""#);
+        let l2: PangoLineage<UndeterminedBaseName> =
+            serde_json::from_str(r#""BA.2.75""#).unwrap();
+        assert_eq!(l2, l);
+        let ls: Vec<PangoLineage<HaplotypeBasename>> =
+            serde_json::from_str(r#"["B.1.1.529.2", "XBB"]"#).unwrap();
+        assert!(ls[0].is_ancestor_of(&lin("B.1.1.529.2.3").force_into_canonicalization(), false));
+        assert_eq!(serde_json::to_string(&ls).unwrap(), r#"["B.1.1.529.2","XBB"]"#);
+        assert!(serde_json::from_str::<PangoLineage<UndeterminedBaseName>>(r#""B.x""#).is_err());
+        assert!(serde_json::from_str::<PangoLineage<UndeterminedBaseName>>("1").is_err());
+        assert!("".parse::<PangoLineage<UndeterminedBaseName>>().is_err());
+    }
+}
+
+#[cfg(test)]
+mod tests_parse_error {
+    use super::*;
+
+    fn err(s: &str) -> LineageParseError {
+        PangoLineage::parse(s, false).unwrap_err()
+    }
+
+    #[test]
+    fn t_parse_errors() {
+        assert_eq!(err(""), LineageParseError::Empty);
+        assert_eq!(err(".1"), LineageParseError::InvalidBaseName("".into()));
+        assert_eq!(err("BAXYZ.1"), LineageParseError::InvalidBaseName("BAXYZ".into()));
+        assert_eq!(err("B1"), LineageParseError::InvalidBaseName("B1".into()));
+        assert_eq!(err("ba.2"), LineageParseError::Lowercase("ba".into()));
+        assert_eq!(err("B..1"), LineageParseError::EmptyComponent("B..1".into()));
+        assert_eq!(err("B.1."), LineageParseError::EmptyComponent("B.1.".into()));
+        assert_eq!(err("B.+1"), LineageParseError::NonNumericComponent {
+            lineage: "B.+1".into(), component: "+1".into() });
+        assert_eq!(err("B.1a"), LineageParseError::NonNumericComponent {
+            lineage: "B.1a".into(), component: "1a".into() });
+        assert_eq!(err("B.65536"), LineageParseError::Overflow {
+            lineage: "B.65536".into(), component: "65536".into() });
+        assert_eq!(err("B..1").to_string(), "empty path component in lineage \"B..1\"");
+        assert!(PangoLineage::parse("B.65535", false).is_ok());
+    }
+
+    #[test]
+    fn t_normalize_case() {
+        assert_eq!(PangoLineage::parse("bA.2.75", true).unwrap().to_string(), "BA.2.75");
+        assert_eq!(PangoLineage::parse("x.1", true).unwrap().to_string(), "X.1");
+        assert_eq!(PangoLineage::parse("ba..1", true).unwrap_err(),
+                   LineageParseError::EmptyComponent("ba..1".into()));
+    }
+}
@@ -0,0 +1,214 @@
+//! Processing the lines of a file on multiple threads while handing
+//! the results on in the original order.
+
+use std::{collections::BTreeMap, io::BufRead, sync::{Arc, Mutex, mpsc::sync_channel}, thread};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+
+/// Number of lines handed to a worker at once.
+pub const CHUNK_SIZE: usize = 1000;
+
+/// How many chunks per worker may be in flight (read but not yet
+/// consumed) at any time; bounds memory use.
+const CHUNKS_IN_FLIGHT_PER_THREAD: usize = 4;
+
+/// Read the next line without the trailing newline. Returns false at
+/// the end of the input.
+fn read_line(inp: &mut impl BufRead, line: &mut String) -> Result<bool> {
+    line.clear();
+    if inp.read_line(line)? == 0 {
+        return Ok(false)
+    }
+    if line.ends_with('\n') {
+        line.pop();
+    }
+    Ok(true)
+}
+
+/// Call `work` with the line number (1-based) and contents (without
+/// the newline) of every line of `inp`, then `consume` with the line
+/// number and the result, in input order. `work` runs on `threads`
+/// worker threads (if `threads` > 1; a reader thread is added),
+/// `consume` on the calling thread. Stops at the first error (in line
+/// order), which is returned with the line number as context.
+pub fn process_lines_ordered<R, T, F, C>(
+    mut inp: R, threads: usize, work: F, mut consume: C
+) -> Result<()>
+where R: BufRead + Send,
+      T: Send,
+      F: Fn(usize, &str) -> Result<T> + Sync,
+      C: FnMut(usize, T) -> Result<()>
+{
+    if threads <= 1 {
+        let mut line = String::new();
+        let mut lineno = 0;
+        return (|| -> Result<_> {
+            while read_line(&mut inp, &mut line)? {
+                lineno += 1;
+                let t = work(lineno, &line)?;
+                consume(lineno, t)?;
+            }
+            Ok(())
+        })().with_context(|| anyhow!("on line {lineno}"))
+    }
+
+    thread::scope(|s| -> Result<()> {
+        let in_flight = threads * CHUNKS_IN_FLIGHT_PER_THREAD;
+        // (chunk index, line number of first line, lines)
+        let (work_tx, work_rx) = sync_channel::<(usize, usize, Vec<String>)>(in_flight);
+        let (result_tx, result_rx) = sync_channel(in_flight);
+        // The reader takes a token for every chunk, the consumer
+        // gives it back when done with the chunk
+        let (token_tx, token_rx) = sync_channel::<()>(in_flight);
+        for _ in 0..in_flight {
+            token_tx.send(()).expect("capacity is sufficient");
+        }
+
+        let reader = s.spawn(move || -> Result<()> {
+            let mut lineno = 0;
+            (|| -> Result<_> {
+                for chunki in 0.. {
+                    let mut lines = Vec::with_capacity(CHUNK_SIZE);
+                    let mut line = String::new();
+                    while lines.len() < CHUNK_SIZE && read_line(&mut inp, &mut line)? {
+                        lines.push(std::mem::take(&mut line));
+                        lineno += 1;
+                    }
+                    if lines.is_empty() {
+                        break
+                    }
+                    let lineno0 = lineno + 1 - lines.len();
+                    if token_rx.recv().is_err()
+                        || work_tx.send((chunki, lineno0, lines)).is_err() {
+                        // The consumer stopped
+                        break
+                    }
+                }
+                Ok(())
+            })().with_context(|| anyhow!("on line {}", lineno + 1))
+        });
+
+        let work_rx = Arc::new(Mutex::new(work_rx));
+        for _ in 0..threads {
+            let work_rx = work_rx.clone();
+            let result_tx = result_tx.clone();
+            let work = &work;
+            s.spawn(move || {
+                loop {
+                    let msg = work_rx.lock().expect("no panics").recv();
+                    let (chunki, lineno0, lines) = match msg {
+                        Ok(msg) => msg,
+                        Err(_) => break
+                    };
+                    // Results up to the first error
+                    let mut results = Vec::with_capacity(lines.len());
+                    let mut error = None;
+                    for (i, line) in lines.iter().enumerate() {
+                        match work(lineno0 + i, line) {
+                            Ok(t) => results.push(t),
+                            Err(e) => {
+                                error = Some(e);
+                                break
+                            }
+                        }
+                    }
+                    if result_tx.send((chunki, lineno0, results, error)).is_err() {
+                        break
+                    }
+                }
+            });
+        }
+        drop(work_rx);
+        drop(result_tx);
+
+        let mut lineno = 0;
+        let consumed = (|| -> Result<_> {
+            let mut pending = BTreeMap::new();
+            let mut next_chunki = 0;
+            for (chunki, lineno0, results, error) in result_rx.iter() {
+                pending.insert(chunki, (lineno0, results, error));
+                while let Some((lineno0, results, error)) = pending.remove(&next_chunki) {
+                    for (i, t) in results.into_iter().enumerate() {
+                        lineno = lineno0 + i;
+                        consume(lineno, t)?;
+                    }
+                    if let Some(e) = error {
+                        lineno += 1;
+                        return Err(e)
+                    }
+                    let _ = token_tx.send(());
+                    next_chunki += 1;
+                }
+            }
+            if ! pending.is_empty() {
+                bail!("bug: chunks missing before chunk {next_chunki}")
+            }
+            Ok(())
+        })().with_context(|| anyhow!("on line {lineno}"));
+        // Make the workers and the reader stop if they are still busy
+        drop(result_rx);
+        drop(token_tx);
+        let read = reader.join().expect("no panics");
+        consumed?;
+        read
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbers(n: usize) -> String {
+        (1..=n).map(|i| format!("{i}\n")).collect()
+    }
+
+    #[test]
+    fn t_order() {
+        for threads in [1, 2, 7] {
+            let input = numbers(CHUNK_SIZE * 5 + 3);
+            let mut out = Vec::new();
+            process_lines_ordered(
+                input.as_bytes(), threads,
+                |lineno, line| {
+                    assert_eq!(line.parse::<usize>().unwrap(), lineno);
+                    Ok(lineno * 2)
+                },
+                |lineno, t| {
+                    assert_eq!(t, lineno * 2);
+                    out.push(lineno);
+                    Ok(())
+                }).unwrap();
+            assert_eq!(out, (1..=CHUNK_SIZE * 5 + 3).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn t_errors() {
+        for threads in [1, 3] {
+            let input = numbers(CHUNK_SIZE * 4);
+            let mut consumed = 0;
+            let e = process_lines_ordered(
+                input.as_bytes(), threads,
+                |lineno, _| if lineno == 2500 || lineno == 3700 {
+                    bail!("bad")
+                } else {
+                    Ok(())
+                },
+                |_, ()| {
+                    consumed += 1;
+                    Ok(())
+                }).unwrap_err();
+            assert_eq!(format!("{e:#}"), "on line 2500: bad");
+            assert_eq!(consumed, 2499);
+
+            let e = process_lines_ordered(
+                input.as_bytes(), threads,
+                |_, _| Ok(()),
+                |lineno, ()| if lineno == 1234 { bail!("dup") } else { Ok(()) }
+            ).unwrap_err();
+            assert_eq!(format!("{e:#}"), "on line 1234: dup");
+        }
+    }
+}
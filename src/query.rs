@@ -0,0 +1,420 @@
+//! The query documents of LAPIS/SILO test cases, e.g.
+//! `{"action": {"type": "Aggregated"}, "filterExpression": {"type":
+//! "True"}}`, parsed into an AST. See `query_engine` for evaluating
+//! them.
+//!
+//! Unknown keys are rejected, so that features not implemented here
+//! aren't silently ignored.
+
+use std::convert::TryFrom;
+
+use chrono::NaiveDate;
+use anyhow::{anyhow, bail, Context, Result};
+use jzon::{JsonValue, object::Object};
+
+use crate::{easyjson::{EasyJsonValue, EasyObject},
+            pangolineage::LineagePattern};
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+    pub action: Action,
+    pub filter: Filter,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// The number of matching rows, per combination of values of the
+    /// `group_by` columns (a single count if empty). Sorted by
+    /// `order_by` (on `group_by` columns or `count`), then by the group
+    /// values, then limited like `Details`.
+    Aggregated {
+        group_by: Vec<String>,
+        order_by: Vec<OrderBy>,
+        limit: Option<usize>,
+        offset: Option<usize>,
+    },
+    /// The matching rows; all columns if `fields` is empty. Sorted by
+    /// `order_by` (or in table order), then `offset` rows skipped and
+    /// at most `limit` returned.
+    Details {
+        fields: Vec<String>,
+        order_by: Vec<OrderBy>,
+        limit: Option<usize>,
+        offset: Option<usize>,
+    },
+    /// Mutation frequencies; needs sequence data
+    Mutations { min_proportion: Option<f64> },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    Ascending,
+    Descending,
+}
+
+/// One of the `orderByFields`, either a field name (ascending) or
+/// `{"field": ..., "order": "ascending"|"descending"}`. Nulls come
+/// first in ascending order, see `TsvValue::total_cmp`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OrderBy {
+    pub field: String,
+    pub order: Order,
+}
+
+impl OrderBy {
+    pub fn from_json(json: &JsonValue) -> Result<OrderBy> {
+        if let Some(field) = json.as_str() {
+            return Ok(OrderBy { field: field.into(), order: Order::Ascending })
+        }
+        let obj = json.object()?;
+        check_keys(obj, &["field", "order"])?;
+        let order = match get_string(obj, "order")?.as_deref() {
+            None | Some("ascending") => Order::Ascending,
+            Some("descending") => Order::Descending,
+            Some(o) => bail!("invalid order {o:?}, expecting \"ascending\" or \"descending\"")
+        };
+        Ok(OrderBy { field: obj.xget("field")?.string()?, order })
+    }
+}
+
+
+/// Every filter selects a set of rows. The value filters select null
+/// cells only when asked for null (`value` null), and never in the
+/// ranges; `Not` selects the complement, thus "not true" includes the
+/// nulls.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    True,
+    /// All rows if empty
+    And(Vec<Filter>),
+    /// No rows if empty
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+    /// At least (or with `match_exactly`, exactly) `n` of `children`
+    /// match
+    NOf { n: usize, match_exactly: bool, children: Vec<Filter> },
+    /// `pattern` None matches rows where `column` is null
+    PangoLineage { column: String, pattern: Option<LineagePattern> },
+    StringEquals { column: String, value: Option<String> },
+    /// The bounds are inclusive, None is unbounded
+    IntBetween { column: String, from: Option<i64>, to: Option<i64> },
+    FloatBetween { column: String, from: Option<f64>, to: Option<f64> },
+    DateBetween { column: String, from: Option<NaiveDate>, to: Option<NaiveDate> },
+    BooleanEquals { column: String, value: Option<bool> },
+}
+
+
+/// Fail if `obj` has keys other than `allowed`.
+fn check_keys(obj: &Object, allowed: &[&str]) -> Result<()> {
+    for (key, _) in obj.iter() {
+        if ! allowed.contains(&key) {
+            bail!("unknown key {key:?}, expecting one of {allowed:?}")
+        }
+    }
+    Ok(())
+}
+
+fn get_bool(obj: &Object, key: &str) -> Result<Option<bool>> {
+    match obj.get(key) {
+        None | Some(JsonValue::Null) => Ok(None),
+        Some(JsonValue::Boolean(v)) => Ok(Some(*v)),
+        Some(v) => bail!("expecting boolean for key {key:?}, got {}", v.dump())
+    }
+}
+
+fn get_i64(obj: &Object, key: &str) -> Result<Option<i64>> {
+    match obj.get(key) {
+        None | Some(JsonValue::Null) => Ok(None),
+        Some(v) => Ok(Some(v.as_i64().ok_or_else(
+            || anyhow!("expecting integer for key {key:?}, got {}", v.dump()))?))
+    }
+}
+
+fn get_f64(obj: &Object, key: &str) -> Result<Option<f64>> {
+    match obj.get(key) {
+        None | Some(JsonValue::Null) => Ok(None),
+        Some(v) => Ok(Some(v.as_f64().ok_or_else(
+            || anyhow!("expecting number for key {key:?}, got {}", v.dump()))?))
+    }
+}
+
+fn get_string(obj: &Object, key: &str) -> Result<Option<String>> {
+    match obj.get(key) {
+        None | Some(JsonValue::Null) => Ok(None),
+        Some(v) => Ok(Some(v.string().with_context(|| anyhow!("key {key:?}"))?))
+    }
+}
+
+fn get_date(obj: &Object, key: &str) -> Result<Option<NaiveDate>> {
+    get_string(obj, key)?.map(|s| NaiveDate::parse_from_str(&s, "%Y-%m-%d").map_err(
+        |e| anyhow!("invalid date {s:?} for key {key:?}: {e}"))).transpose()
+}
+
+fn get_usize(obj: &Object, key: &str) -> Result<Option<usize>> {
+    match obj.get(key) {
+        None | Some(JsonValue::Null) => Ok(None),
+        Some(v) => Ok(Some(v.as_usize().ok_or_else(
+            || anyhow!("expecting non-negative integer for key {key:?}, got {}", v.dump()))?))
+    }
+}
+
+fn get_order_by(obj: &Object) -> Result<Vec<OrderBy>> {
+    match obj.get("orderByFields") {
+        None | Some(JsonValue::Null) => Ok(Vec::new()),
+        Some(JsonValue::Array(vs)) => vs.iter().map(OrderBy::from_json).collect::<Result<_>>()
+            .with_context(|| anyhow!("key \"orderByFields\"")),
+        Some(v) => bail!("expecting array for key \"orderByFields\", got {}", v.dump())
+    }
+}
+
+fn get_strings(obj: &Object, key: &str) -> Result<Vec<String>> {
+    match obj.get(key) {
+        None | Some(JsonValue::Null) => Ok(Vec::new()),
+        Some(JsonValue::Array(vs)) => vs.iter().map(|v| v.string()).collect::<Result<_>>()
+            .with_context(|| anyhow!("key {key:?}")),
+        Some(v) => bail!("expecting array for key {key:?}, got {}", v.dump())
+    }
+}
+
+fn get_column(obj: &Object) -> Result<String> {
+    obj.xget("column")?.string()
+}
+
+fn get_filters(obj: &Object) -> Result<Vec<Filter>> {
+    match obj.xget("children")? {
+        JsonValue::Array(vs) => vs.iter().map(Filter::from_json).collect(),
+        v => bail!("expecting array for key \"children\", got {}", v.dump())
+    }
+}
+
+impl Action {
+    pub fn from_json(json: &JsonValue) -> Result<Action> {
+        let obj = json.object()?;
+        let typ = obj.xget("type")?.str()?;
+        (|| -> Result<_> {
+            Ok(match typ {
+                "Aggregated" => {
+                    check_keys(obj, &["type", "groupByFields", "orderByFields", "limit", "offset"])?;
+                    Action::Aggregated {
+                        group_by: get_strings(obj, "groupByFields")?,
+                        order_by: get_order_by(obj)?,
+                        limit: get_usize(obj, "limit")?,
+                        offset: get_usize(obj, "offset")?,
+                    }
+                }
+                "Details" => {
+                    check_keys(obj, &["type", "fields", "orderByFields", "limit", "offset"])?;
+                    Action::Details {
+                        fields: get_strings(obj, "fields")?,
+                        order_by: get_order_by(obj)?,
+                        limit: get_usize(obj, "limit")?,
+                        offset: get_usize(obj, "offset")?,
+                    }
+                }
+                "Mutations" => {
+                    check_keys(obj, &["type", "minProportion"])?;
+                    Action::Mutations { min_proportion: get_f64(obj, "minProportion")? }
+                }
+                _ => bail!("unknown action type")
+            })
+        })().with_context(|| anyhow!("action {typ:?}"))
+    }
+}
+
+impl Filter {
+    pub fn from_json(json: &JsonValue) -> Result<Filter> {
+        let obj = json.object()?;
+        let typ = obj.xget("type")?.str()?;
+        (|| -> Result<_> {
+            Ok(match typ {
+                "True" => {
+                    check_keys(obj, &["type"])?;
+                    Filter::True
+                }
+                "And" | "Or" => {
+                    check_keys(obj, &["type", "children"])?;
+                    let children = get_filters(obj)?;
+                    if typ == "And" { Filter::And(children) } else { Filter::Or(children) }
+                }
+                "Not" => {
+                    check_keys(obj, &["type", "child"])?;
+                    Filter::Not(Box::new(Filter::from_json(obj.xget("child")?)?))
+                }
+                "N-Of" => {
+                    check_keys(obj, &["type", "numberOfMatchers", "matchExactly", "children"])?;
+                    let n = obj.xget("numberOfMatchers")?;
+                    Filter::NOf {
+                        n: n.as_usize().ok_or_else(
+                            || anyhow!("invalid numberOfMatchers {}", n.dump()))?,
+                        match_exactly: get_bool(obj, "matchExactly")?.unwrap_or(false),
+                        children: get_filters(obj)?,
+                    }
+                }
+                "PangoLineage" => {
+                    check_keys(obj, &["type", "column", "value", "includeSublineages"])?;
+                    let pattern = match obj.get("value") {
+                        None | Some(JsonValue::Null) => None,
+                        Some(v) => {
+                            let p = LineagePattern::try_from(v.str()?)?;
+                            Some(match (p, get_bool(obj, "includeSublineages")?) {
+                                (LineagePattern::Exact(l), Some(true)) =>
+                                    LineagePattern::WithSublineages(l),
+                                (p, _) => p,
+                            })
+                        }
+                    };
+                    Filter::PangoLineage { column: get_column(obj)?, pattern }
+                }
+                "StringEquals" => {
+                    check_keys(obj, &["type", "column", "value"])?;
+                    Filter::StringEquals { column: get_column(obj)?, value: get_string(obj, "value")? }
+                }
+                "IntBetween" => {
+                    check_keys(obj, &["type", "column", "from", "to"])?;
+                    Filter::IntBetween {
+                        column: get_column(obj)?,
+                        from: get_i64(obj, "from")?,
+                        to: get_i64(obj, "to")?,
+                    }
+                }
+                "FloatBetween" => {
+                    check_keys(obj, &["type", "column", "from", "to"])?;
+                    Filter::FloatBetween {
+                        column: get_column(obj)?,
+                        from: get_f64(obj, "from")?,
+                        to: get_f64(obj, "to")?,
+                    }
+                }
+                "DateBetween" => {
+                    check_keys(obj, &["type", "column", "from", "to"])?;
+                    Filter::DateBetween {
+                        column: get_column(obj)?,
+                        from: get_date(obj, "from")?,
+                        to: get_date(obj, "to")?,
+                    }
+                }
+                "BooleanEquals" => {
+                    check_keys(obj, &["type", "column", "value"])?;
+                    Filter::BooleanEquals { column: get_column(obj)?, value: get_bool(obj, "value")? }
+                }
+                _ => bail!("unknown filter type")
+            })
+        })().with_context(|| anyhow!("filter expression {typ:?}"))
+    }
+}
+
+impl Query {
+    pub fn from_json(json: &JsonValue) -> Result<Query> {
+        let obj = json.object()?;
+        check_keys(obj, &["action", "filterExpression"])?;
+        Ok(Query {
+            action: Action::from_json(obj.xget("action")?)?,
+            filter: Filter::from_json(obj.xget("filterExpression")?)?,
+        })
+    }
+
+    /// Whether the query defines the order of the result rows
+    /// (otherwise it's unspecified).
+    pub fn is_ordered(&self) -> bool {
+        match &self.action {
+            Action::Details { order_by, .. } | Action::Aggregated { order_by, .. } =>
+                ! order_by.is_empty(),
+            Action::Mutations { .. } => false,
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Query> {
+        Query::from_json(&jzon::parse(s)?).with_context(|| anyhow!("parsing query"))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn t_parse() {
+        let q = Query::parse(r#"{
+            "action": {"type": "Aggregated"},
+            "filterExpression": {"type": "PangoLineage", "column": "pango_lineage",
+                                 "value": "B.1.1.7", "includeSublineages": true}
+        }"#).unwrap();
+        assert_eq!(q.action, Action::Aggregated {
+            group_by: vec![], order_by: vec![], limit: None, offset: None });
+        assert_eq!(q.filter, Filter::PangoLineage {
+            column: "pango_lineage".into(),
+            pattern: Some(LineagePattern::try_from("B.1.1.7*").unwrap()),
+        });
+
+        let q = Query::parse(r#"{
+            "action": {"type": "Details", "fields": ["date", "country"]},
+            "filterExpression": {"type": "PangoLineage", "column": "pango_lineage", "value": null}
+        }"#).unwrap();
+        assert_eq!(q.action, Action::Details {
+            fields: vec!["date".into(), "country".into()],
+            order_by: vec![],
+            limit: None,
+            offset: None,
+        });
+        assert!(! q.is_ordered());
+        assert_eq!(q.filter, Filter::PangoLineage { column: "pango_lineage".into(), pattern: None });
+
+        let q = Query::parse(r#"{
+            "action": {"type": "Details", "limit": 10, "offset": 2,
+                       "orderByFields": ["date", {"field": "age", "order": "descending"}]},
+            "filterExpression": {"type": "True"}
+        }"#).unwrap();
+        assert_eq!(q.action, Action::Details {
+            fields: vec![],
+            order_by: vec![OrderBy { field: "date".into(), order: Order::Ascending },
+                           OrderBy { field: "age".into(), order: Order::Descending }],
+            limit: Some(10),
+            offset: Some(2),
+        });
+        assert!(q.is_ordered());
+
+        let err = |s: &str| format!("{:#}", Query::parse(s).unwrap_err());
+        assert_eq!(
+            err(r#"{"action": {"type": "Aggregated", "fields": []}, "filterExpression": {"type": "True"}}"#),
+            r#"parsing query: action "Aggregated": unknown key "fields", expecting one of ["type", "groupByFields", "orderByFields", "limit", "offset"]"#);
+        assert_eq!(
+            err(r#"{"action": {"type": "Foo"}, "filterExpression": {"type": "True"}}"#),
+            r#"parsing query: action "Foo": unknown action type"#);
+        assert!(Query::parse(r#"{"action": {"type": "Aggregated"}}"#).is_err());
+
+        let q = Query::parse(r#"{
+            "action": {"type": "Aggregated"},
+            "filterExpression": {"type": "N-Of", "numberOfMatchers": 1, "matchExactly": true,
+              "children": [
+                {"type": "Not", "child": {"type": "BooleanEquals", "column": "b", "value": null}},
+                {"type": "And", "children": [
+                  {"type": "StringEquals", "column": "country", "value": "Switzerland"},
+                  {"type": "IntBetween", "column": "age", "from": 10},
+                  {"type": "FloatBetween", "column": "qc_value", "from": null, "to": 0.5},
+                  {"type": "DateBetween", "column": "date", "from": "2021-03-01", "to": "2021-03-31"}
+                ]},
+                {"type": "Or", "children": []}
+              ]}
+        }"#).unwrap();
+        let date = |s| Some(NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap());
+        assert_eq!(q.filter, Filter::NOf { n: 1, match_exactly: true, children: vec![
+            Filter::Not(Box::new(Filter::BooleanEquals { column: "b".into(), value: None })),
+            Filter::And(vec![
+                Filter::StringEquals { column: "country".into(), value: Some("Switzerland".into()) },
+                Filter::IntBetween { column: "age".into(), from: Some(10), to: None },
+                Filter::FloatBetween { column: "qc_value".into(), from: None, to: Some(0.5) },
+                Filter::DateBetween {
+                    column: "date".into(), from: date("2021-03-01"), to: date("2021-03-31") },
+            ]),
+            Filter::Or(vec![]),
+        ]});
+        assert_eq!(
+            err(r#"{"action": {"type": "Aggregated"}, "filterExpression": {"type": "Not",
+                    "child": {"type": "IntBetween", "column": "age", "from": 1.5}}}"#),
+            r#"parsing query: filter expression "Not": filter expression "IntBetween": expecting integer for key "from", got 1.5"#);
+        assert!(Query::parse(r#"{"action": {"type": "Aggregated"}, "filterExpression":
+             {"type": "PangoLineage", "column": "c", "value": "B..1"}}"#).is_err());
+    }
+}
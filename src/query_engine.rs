@@ -0,0 +1,558 @@
+//! Evaluation of `query::Query` against the rows of a TSV file, to
+//! compute the expected results of test cases mechanically.
+
+use std::{cmp::Ordering, collections::HashMap};
+
+use anyhow::{anyhow, bail, Context, Result};
+use itertools::Itertools;
+use jzon::JsonValue;
+use kstring::KString;
+
+use crate::{groupby::group_by,
+            lineage_interner::{LineageId, LineageInterner},
+            lineagelist_index::LineageAliases,
+            pangolineage::{LineageName, LineageQuery},
+            query::{Action, Filter, Order, Query},
+            tsv::Tsv,
+            tsv_value::{ColumnType, TsvValue, TsvValueParser}};
+
+
+#[derive(Debug)]
+pub struct Column {
+    pub name: KString,
+    pub column_type: ColumnType,
+    pub values: Vec<TsvValue>,
+}
+
+/// A TSV file with its cells parsed into typed values.
+#[derive(Debug)]
+pub struct Table {
+    pub columns: Vec<Column>,
+    len: usize,
+}
+
+impl Table {
+    /// Parse all cells of `tsv`; columns not listed in
+    /// `column_types` get the type inferred by
+    /// `TsvValueParser::infer_column_type`.
+    pub fn from_tsv(
+        tsv: &Tsv, parser: &TsvValueParser, column_types: &HashMap<String, ColumnType>
+    ) -> Result<Table> {
+        let columns = tsv.headers.iter().enumerate().map(|(i, name)| {
+            let column_type = column_types.get(name).copied().unwrap_or_else(
+                || parser.infer_column_type(tsv.rows.iter().map(|r| &r[i])));
+            parser.warn_invalid(column_type, tsv, i);
+            let values = tsv.rows.iter().enumerate().map(
+                |(rowi, r)| parser.parse(column_type, &r[i], name, tsv.line_number(rowi)))
+                .collect::<Result<_, _>>()?;
+            Ok(Column { name: KString::from_ref(name), column_type, values })
+        }).collect::<Result<_>>().with_context(|| anyhow!("parsing {:?}", tsv.path))?;
+        Ok(Table { columns, len: tsv.rows.len() })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn column(&self, name: &str) -> Result<&Column> {
+        self.columns.iter().find(|c| c.name == name).ok_or_else(
+            || anyhow!("unknown column {name:?}"))
+    }
+}
+
+
+/// Whether `v` is within the inclusive bounds, None being unbounded.
+fn in_range<T: PartialOrd>(v: T, from: Option<T>, to: Option<T>) -> bool {
+    from.is_none_or(|from| from <= v) && to.is_none_or(|to| v <= to)
+}
+
+type Cmp<'a, T> = Box<dyn Fn(&T, &T) -> Ordering + 'a>;
+
+/// Sort `items` stably by the first of `keys` that doesn't compare
+/// them as equal.
+fn sort_by_keys<T>(items: &mut [T], keys: &[(Cmp<T>, Order)]) {
+    items.sort_by(|a, b| {
+        for (cmp, order) in keys {
+            let ord = match order {
+                Order::Ascending => cmp(a, b),
+                Order::Descending => cmp(b, a),
+            };
+            if ord != Ordering::Equal {
+                return ord
+            }
+        }
+        Ordering::Equal
+    });
+}
+
+pub struct QueryEngine<'t> {
+    table: &'t Table,
+    interner: LineageInterner<'t>,
+    /// Column name -> lineage ID per row, for the `Lineage` columns
+    lineage_ids: HashMap<KString, Vec<Option<LineageId>>>,
+}
+
+impl<'t> QueryEngine<'t> {
+    /// Interns the values of all `Lineage` columns of `table`.
+    pub fn new(table: &'t Table, aliases: &'t LineageAliases) -> Result<Self> {
+        let mut interner = LineageInterner::new(aliases);
+        let mut lineage_ids = HashMap::new();
+        for column in table.columns.iter().filter(|c| c.column_type == ColumnType::Lineage) {
+            let ids = column.values.iter().map(|v| match v {
+                TsvValue::String(s) => Ok(Some(interner.intern(s)?)),
+                _ => Ok(None),
+            }).collect::<Result<_>>().with_context(
+                || anyhow!("column {:?}", column.name))?;
+            lineage_ids.insert(column.name.clone(), ids);
+        }
+        interner.number();
+        Ok(QueryEngine { table, interner, lineage_ids })
+    }
+
+    /// Whether `pred` holds for the values of `column`, which must be
+    /// of one of `types` (or `Auto`).
+    fn select(
+        &self, column: &str, types: &[ColumnType], pred: impl Fn(&TsvValue) -> bool
+    ) -> Result<Vec<bool>> {
+        let c = self.table.column(column)?;
+        if c.column_type != ColumnType::Auto && ! types.contains(&c.column_type) {
+            bail!("column {column:?} is of type {}, expecting {}",
+                  c.column_type, types.iter().map(|t| t.as_str()).join(" or "))
+        }
+        Ok(c.values.iter().map(pred).collect())
+    }
+
+    /// Which rows match `filter`.
+    pub fn filter(&self, filter: &Filter) -> Result<Vec<bool>> {
+        Ok(match filter {
+            Filter::True => vec![true; self.table.len()],
+            Filter::And(children) => {
+                let mut selected = vec![true; self.table.len()];
+                for child in children {
+                    for (s, c) in selected.iter_mut().zip(self.filter(child)?) {
+                        *s &= c;
+                    }
+                }
+                selected
+            }
+            Filter::Or(children) => {
+                let mut selected = vec![false; self.table.len()];
+                for child in children {
+                    for (s, c) in selected.iter_mut().zip(self.filter(child)?) {
+                        *s |= c;
+                    }
+                }
+                selected
+            }
+            Filter::Not(child) => self.filter(child)?.into_iter().map(|s| ! s).collect(),
+            Filter::NOf { n, match_exactly, children } => {
+                let mut counts = vec![0; self.table.len()];
+                for child in children {
+                    for (count, c) in counts.iter_mut().zip(self.filter(child)?) {
+                        *count += c as usize;
+                    }
+                }
+                counts.into_iter().map(
+                    |count| if *match_exactly { count == *n } else { count >= *n }).collect()
+            }
+            Filter::PangoLineage { column, pattern } => {
+                let ids = self.lineage_ids.get(column.as_str()).ok_or_else(|| {
+                    match self.table.column(column) {
+                        Ok(c) => anyhow!("column {column:?} is of type {}, not lineage",
+                                         c.column_type),
+                        Err(e) => e
+                    }
+                })?;
+                match pattern {
+                    None => ids.iter().map(Option::is_none).collect(),
+                    Some(pattern) => {
+                        let query = self.interner.compile_query(&LineageQuery {
+                            include: vec![pattern.clone()],
+                            exclude: Vec::new(),
+                        });
+                        ids.iter().map(
+                            |id| id.is_some_and(|id| query.matches(&self.interner, id)))
+                            .collect()
+                    }
+                }
+            }
+            Filter::StringEquals { column, value } =>
+                self.select(column, &[ColumnType::String, ColumnType::Lineage], |v| match value {
+                    None => v.is_null(),
+                    Some(value) => matches!(v, TsvValue::String(s) if s == value),
+                })?,
+            Filter::IntBetween { column, from, to } =>
+                self.select(column, &[ColumnType::Int], |v| match v {
+                    TsvValue::Int(v) => in_range(*v, *from, *to),
+                    _ => false
+                })?,
+            Filter::FloatBetween { column, from, to } =>
+                self.select(column, &[ColumnType::Float, ColumnType::Int], |v| match v {
+                    TsvValue::Float(v) => in_range(*v, *from, *to),
+                    TsvValue::Int(v) => in_range(*v as f64, *from, *to),
+                    _ => false
+                })?,
+            Filter::DateBetween { column, from, to } =>
+                self.select(column, &[ColumnType::Date], |v| match v {
+                    TsvValue::Date(v) => in_range(*v, *from, *to),
+                    _ => false
+                })?,
+            Filter::BooleanEquals { column, value } =>
+                self.select(column, &[ColumnType::Bool], |v| match value {
+                    None => v.is_null(),
+                    Some(value) => *v == TsvValue::Bool(*value),
+                })?,
+        })
+    }
+
+    /// Compares rows by their values in `column`, lineages by their
+    /// components rather than as strings.
+    fn column_cmp(&self, column: &str) -> Result<Cmp<'_, usize>> {
+        let column = self.table.column(column)?;
+        Ok(if column.column_type == ColumnType::Lineage {
+            let lineages: Vec<_> = column.values.iter().map(|v| match v {
+                TsvValue::String(s) => LineageName::parse(s, false).ok().map(
+                    |n| (n.lineage, n.withdrawn)),
+                _ => None
+            }).collect();
+            Box::new(move |a, b| lineages[*a].cmp(&lineages[*b]))
+        } else {
+            Box::new(move |a, b| column.values[*a].total_cmp(&column.values[*b]))
+        })
+    }
+
+    /// The result of `query`, in the form of `expectedQueryResult`
+    /// in test cases.
+    pub fn run(&self, query: &Query) -> Result<JsonValue> {
+        let selected = self.filter(&query.filter)?;
+        let rows = || selected.iter().enumerate().filter(|(_, s)| **s).map(|(i, _)| i);
+        Ok(match &query.action {
+            Action::Aggregated { group_by: fields, order_by, limit, offset } => {
+                let columns: Vec<&Column> = fields.iter().map(
+                    |f| self.table.column(f)).collect::<Result<_>>()?;
+                let rows: Vec<usize> = rows().collect();
+                // (first row of the group, count)
+                let mut groups: Vec<(usize, usize)> = if columns.is_empty() {
+                    vec![(0, rows.len())]
+                } else {
+                    group_by(
+                        rows.iter(),
+                        |i| columns.iter().map(|c| &c.values[*i]).collect::<Vec<_>>(),
+                        |i| i
+                    )?.into_values().map(
+                        |g| (**g.keys().min().expect("groups are not empty"), g.len())
+                    ).collect()
+                };
+                let group_cmp = |field: &str| -> Result<Cmp<(usize, usize)>> {
+                    let cmp = self.column_cmp(field)?;
+                    Ok(Box::new(move |a, b| cmp(&a.0, &b.0)))
+                };
+                let mut keys = order_by.iter().map(|o| {
+                    let cmp = if fields.contains(&o.field) {
+                        group_cmp(&o.field)?
+                    } else if o.field == "count" {
+                        Box::new(|a: &(usize, usize), b: &(usize, usize)| a.1.cmp(&b.1))
+                    } else {
+                        bail!("can only order by groupByFields or \"count\", not {:?}", o.field)
+                    };
+                    Ok((cmp, o.order))
+                }).collect::<Result<Vec<_>>>()?;
+                // The groups come in random order, thus always break ties
+                // by the group values
+                for f in fields {
+                    keys.push((group_cmp(f)?, Order::Ascending));
+                }
+                sort_by_keys(&mut groups, &keys);
+                let groups = groups.into_iter()
+                    .skip(offset.unwrap_or(0))
+                    .take(limit.unwrap_or(usize::MAX));
+                JsonValue::Array(groups.map(|(i, count)| {
+                    let mut group = JsonValue::new_object();
+                    for c in &columns {
+                        group.insert(&c.name, c.values[i].to_json()).expect("object");
+                    }
+                    group.insert("count", count).expect("object");
+                    group
+                }).collect())
+            }
+            Action::Details { fields, order_by, limit, offset } => {
+                let columns: Vec<&Column> = if fields.is_empty() {
+                    self.table.columns.iter().collect()
+                } else {
+                    fields.iter().map(|f| self.table.column(f)).collect::<Result<_>>()?
+                };
+                let mut rows: Vec<usize> = rows().collect();
+                let keys = order_by.iter().map(|o| Ok((self.column_cmp(&o.field)?, o.order)))
+                    .collect::<Result<Vec<_>>>()?;
+                sort_by_keys(&mut rows, &keys);
+                let rows = rows.into_iter()
+                    .skip(offset.unwrap_or(0))
+                    .take(limit.unwrap_or(usize::MAX));
+                JsonValue::Array(rows.map(|i| {
+                    let mut row = JsonValue::new_object();
+                    for c in &columns {
+                        row.insert(&c.name, c.values[i].to_json()).expect("object");
+                    }
+                    row
+                }).collect())
+            }
+            Action::Mutations { .. } =>
+                bail!("the Mutations action is not supported, it needs sequence data"),
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use csv::StringRecord;
+
+    use super::*;
+
+    fn table(rows: &[&[&str]]) -> Table {
+        let tsv = Tsv {
+            path: "t.tsv".into(),
+            headers: StringRecord::from(rows[0].to_vec()),
+            rows: rows[1..].iter().map(|r| StringRecord::from(r.to_vec())).collect(),
+        };
+        let mut types = HashMap::new();
+        types.insert("pango_lineage".into(), ColumnType::Lineage);
+        Table::from_tsv(&tsv, &TsvValueParser::default(), &types).unwrap()
+    }
+
+    #[test]
+    fn t_run() {
+        let table = table(&[
+            &["id", "pango_lineage", "age"],
+            &["E1", "B.1.1.7", "3"],
+            &["E2", "Q.1", ""],
+            &["E3", "B.1.1", "40"],
+            &["E4", "", "5"],
+            &["E5", "BA.2", "7"],
+        ]);
+        assert_eq!(table.column("age").unwrap().column_type, ColumnType::Int);
+        assert_eq!(table.column("id").unwrap().column_type, ColumnType::String);
+        let aliases = LineageAliases::from_alias_key_json(&jzon::parse(r#"{
+            "A": "", "B": "", "BA": "B.1.1.529", "Q": "B.1.1.7"
+        }"#).unwrap()).unwrap();
+        let engine = QueryEngine::new(&table, &aliases).unwrap();
+        let run = |s: &str| engine.run(&Query::parse(s).unwrap()).map(|v| v.dump());
+
+        let lineage_filter = |value: &str, sub: bool| format!(
+            r#"{{"type": "PangoLineage", "column": "pango_lineage", "value": {value},
+                 "includeSublineages": {sub}}}"#);
+        let count = |filter: &str| run(&format!(
+            r#"{{"action": {{"type": "Aggregated"}}, "filterExpression": {filter}}}"#)).unwrap();
+        assert_eq!(count(r#"{"type": "True"}"#), r#"[{"count":5}]"#);
+        assert_eq!(count(&lineage_filter(r#""B.1.1.7""#, true)), r#"[{"count":2}]"#);
+        assert_eq!(count(&lineage_filter(r#""B.1.1.7""#, false)), r#"[{"count":1}]"#);
+        assert_eq!(count(&lineage_filter(r#""B.1.1""#, true)), r#"[{"count":4}]"#);
+        assert_eq!(count(&lineage_filter(r#""B.1.1*""#, false)), r#"[{"count":4}]"#);
+        assert_eq!(count(&lineage_filter(r#""XBB""#, true)), r#"[{"count":0}]"#);
+        assert_eq!(count(&lineage_filter("null", false)), r#"[{"count":1}]"#);
+
+        assert_eq!(
+            run(&format!(r#"{{"action": {{"type": "Details", "fields": ["age", "id"]}},
+                             "filterExpression": {}}}"#, lineage_filter(r#""Q""#, true))).unwrap(),
+            r#"[{"age":3,"id":"E1"},{"age":null,"id":"E2"}]"#);
+        assert_eq!(
+            run(r#"{"action": {"type": "Details"},
+                    "filterExpression": {"type": "PangoLineage", "column": "pango_lineage",
+                                         "value": "BA.2"}}"#).unwrap(),
+            r#"[{"id":"E5","pango_lineage":"BA.2","age":7}]"#);
+        assert!(run(r#"{"action": {"type": "Details", "fields": ["x"]},
+                        "filterExpression": {"type": "True"}}"#).is_err());
+        assert!(run(r#"{"action": {"type": "Mutations"},
+                        "filterExpression": {"type": "True"}}"#).is_err());
+        assert_eq!(
+            format!("{:#}", run(r#"{"action": {"type": "Aggregated"},
+                    "filterExpression": {"type": "PangoLineage", "column": "id", "value": "A"}}"#)
+                    .unwrap_err()),
+            r#"column "id" is of type string, not lineage"#);
+    }
+
+    #[test]
+    fn t_withdrawn() {
+        let table = table(&[
+            &["id", "pango_lineage"],
+            &["E1", "*J.1"],
+            &["E2", "J.2"],
+            &["E3", "J"],
+            &["E4", "*Q.3"],
+        ]);
+        let lineages = &table.column("pango_lineage").unwrap().values;
+        assert_eq!(lineages[0], TsvValue::String("*J.1".into()));
+        let aliases = LineageAliases::from_alias_key_json(&jzon::parse(r#"{
+            "B": "", "J": "", "Q": "B.1.1.7"
+        }"#).unwrap()).unwrap();
+        let engine = QueryEngine::new(&table, &aliases).unwrap();
+        let ids = |filter: &str| engine.run(&Query::parse(&format!(
+            r#"{{"action": {{"type": "Details", "fields": ["id"]}},
+                 "filterExpression": {{"type": "PangoLineage", "column": "pango_lineage",
+                                       {filter}}}}}"#)).unwrap()).unwrap().dump();
+        // Withdrawn lineages count as their replacement, the parent here
+        assert_eq!(ids(r#""value": "J""#), r#"[{"id":"E1"},{"id":"E3"}]"#);
+        assert_eq!(ids(r#""value": "J", "includeSublineages": true"#),
+                   r#"[{"id":"E1"},{"id":"E2"},{"id":"E3"}]"#);
+        assert_eq!(ids(r#""value": "J.1", "includeSublineages": true"#), "[]");
+        assert_eq!(ids(r#""value": "B.1.1.7""#), r#"[{"id":"E4"}]"#);
+    }
+
+    #[test]
+    fn t_filters() {
+        let table = table(&[
+            &["id", "country", "age", "qc_value", "date", "b"],
+            &["E1", "Switzerland", "3", "0.5", "2021-03-01", "true"],
+            &["E2", "Germany", "", "1", "2021-03-31", "false"],
+            &["E3", "", "40", "", "2021-04-01", ""],
+            &["E4", "Switzerland", "10", "0.98", "", "false"],
+        ]);
+        assert_eq!(table.column("qc_value").unwrap().column_type, ColumnType::Float);
+        let aliases = LineageAliases::from_alias_key_json(&jzon::parse("{}").unwrap()).unwrap();
+        let engine = QueryEngine::new(&table, &aliases).unwrap();
+        let ids = |filter: &str| -> Result<String> {
+            let query = Query::parse(&format!(
+                r#"{{"action": {{"type": "Details", "fields": ["id"]}}, "filterExpression": {filter}}}"#))?;
+            Ok(engine.run(&query)?.members().map(|r| r["id"].to_string()).join(","))
+        };
+        let b = |value: &str| format!(r#"{{"type": "BooleanEquals", "column": "b", "value": {value}}}"#);
+        let not = |filter: &str| format!(r#"{{"type": "Not", "child": {filter}}}"#);
+        assert_eq!(ids(&b("true")).unwrap(), "E1");
+        assert_eq!(ids(&b("false")).unwrap(), "E2,E4");
+        assert_eq!(ids(&b("null")).unwrap(), "E3");
+        // The complement, including null
+        assert_eq!(ids(&not(&b("true"))).unwrap(), "E2,E3,E4");
+
+        let swiss = r#"{"type": "StringEquals", "column": "country", "value": "Switzerland"}"#;
+        assert_eq!(ids(swiss).unwrap(), "E1,E4");
+        assert_eq!(ids(r#"{"type": "StringEquals", "column": "country", "value": null}"#).unwrap(),
+                   "E3");
+        assert_eq!(ids(r#"{"type": "IntBetween", "column": "age", "from": 3, "to": 10}"#).unwrap(),
+                   "E1,E4");
+        assert_eq!(ids(r#"{"type": "IntBetween", "column": "age", "from": 10}"#).unwrap(),
+                   "E3,E4");
+        assert_eq!(ids(r#"{"type": "IntBetween", "column": "age"}"#).unwrap(), "E1,E3,E4");
+        assert_eq!(ids(r#"{"type": "FloatBetween", "column": "qc_value", "to": 0.98}"#).unwrap(),
+                   "E1,E4");
+        assert_eq!(ids(r#"{"type": "DateBetween", "column": "date",
+                           "from": "2021-03-01", "to": "2021-03-31"}"#).unwrap(), "E1,E2");
+        assert_eq!(ids(r#"{"type": "DateBetween", "column": "date", "from": "2021-03-02"}"#)
+                   .unwrap(), "E2,E3");
+
+        let n_of = |n: usize, exactly: bool| format!(
+            r#"{{"type": "N-Of", "numberOfMatchers": {n}, "matchExactly": {exactly},
+                 "children": [{swiss}, {}, {}]}}"#,
+            b("false"), r#"{"type": "IntBetween", "column": "age", "to": 5}"#);
+        assert_eq!(ids(&n_of(1, false)).unwrap(), "E1,E2,E4");
+        assert_eq!(ids(&n_of(1, true)).unwrap(), "E2");
+        assert_eq!(ids(&n_of(2, true)).unwrap(), "E1,E4");
+        assert_eq!(ids(&n_of(0, true)).unwrap(), "E3");
+        assert_eq!(ids(&format!(r#"{{"type": "And", "children": [{swiss}, {}]}}"#, b("false")))
+                   .unwrap(), "E4");
+        assert_eq!(ids(&format!(r#"{{"type": "Or", "children": [{swiss}, {}]}}"#, b("null")))
+                   .unwrap(), "E1,E3,E4");
+        assert_eq!(ids(r#"{"type": "And", "children": []}"#).unwrap(), "E1,E2,E3,E4");
+        assert_eq!(ids(r#"{"type": "Or", "children": []}"#).unwrap(), "");
+
+        assert_eq!(
+            format!("{:#}", ids(r#"{"type": "IntBetween", "column": "country", "from": 1}"#)
+                    .unwrap_err()),
+            r#"column "country" is of type string, expecting int"#);
+    }
+
+    #[test]
+    fn t_details() {
+        let table = table(&[
+            &["id", "pango_lineage", "age", "date"],
+            &["E1", "B.1.10", "3", "2021-03-01"],
+            &["E2", "B.1.2", "", "2021-03-01"],
+            &["E3", "", "40", "2021-01-05"],
+            &["E4", "B.1.2", "10", ""],
+            &["E5", "A.1", "3", "2021-03-02"],
+        ]);
+        let aliases = LineageAliases::from_alias_key_json(&jzon::parse("{}").unwrap()).unwrap();
+        let engine = QueryEngine::new(&table, &aliases).unwrap();
+        let ids = |action: &str| -> Result<String> {
+            let query = Query::parse(&format!(
+                r#"{{"action": {action}, "filterExpression": {{"type": "True"}}}}"#))?;
+            Ok(engine.run(&query)?.members().map(|r| r["id"].to_string()).join(","))
+        };
+        let order_by = |order_by: &str| ids(&format!(
+            r#"{{"type": "Details", "fields": ["id"], "orderByFields": {order_by}}}"#)).unwrap();
+        assert_eq!(order_by(r#"["age"]"#), "E2,E1,E5,E4,E3");
+        assert_eq!(order_by(r#"[{"field": "age", "order": "descending"}]"#), "E3,E4,E1,E5,E2");
+        assert_eq!(order_by(r#"[{"field": "age"}, {"field": "date", "order": "descending"}]"#),
+                   "E2,E5,E1,E4,E3");
+        // By lineage components, not as strings
+        assert_eq!(order_by(r#"["pango_lineage", "id"]"#), "E3,E5,E2,E4,E1");
+        assert_eq!(order_by(r#"["date"]"#), "E4,E3,E1,E2,E5");
+
+        assert_eq!(ids(r#"{"type": "Details", "orderByFields": ["id"], "limit": 2}"#).unwrap(),
+                   "E1,E2");
+        assert_eq!(ids(r#"{"type": "Details", "orderByFields": ["age"], "offset": 1, "limit": 3}"#)
+                   .unwrap(), "E1,E5,E4");
+        assert_eq!(ids(r#"{"type": "Details", "offset": 4, "limit": 3}"#).unwrap(), "E5");
+        assert_eq!(ids(r#"{"type": "Details", "offset": 9}"#).unwrap(), "");
+        assert!(ids(r#"{"type": "Details", "orderByFields": ["x"]}"#).is_err());
+    }
+
+    #[test]
+    fn t_aggregated() {
+        let table = table(&[
+            &["id", "country", "pango_lineage", "age"],
+            &["E1", "Switzerland", "B.1.10", "3"],
+            &["E2", "Germany", "B.1.2", ""],
+            &["E3", "", "B.1.2", "40"],
+            &["E4", "Switzerland", "B.1.2", "10"],
+            &["E5", "Switzerland", "B.1.10", "3"],
+        ]);
+        let aliases = LineageAliases::from_alias_key_json(&jzon::parse("{}").unwrap()).unwrap();
+        let engine = QueryEngine::new(&table, &aliases).unwrap();
+        let run = |action: &str, filter: &str| -> Result<String> {
+            let query = Query::parse(&format!(
+                r#"{{"action": {action}, "filterExpression": {filter}}}"#))?;
+            Ok(engine.run(&query)?.dump())
+        };
+        let all = r#"{"type": "True"}"#;
+        assert_eq!(run(r#"{"type": "Aggregated", "groupByFields": ["country"]}"#, all).unwrap(),
+                   r#"[{"country":null,"count":1},{"country":"Germany","count":1},{"country":"Switzerland","count":3}]"#);
+        assert_eq!(run(r#"{"type": "Aggregated", "groupByFields": ["pango_lineage", "country"],
+                           "orderByFields": [{"field": "count", "order": "descending"}, "country"],
+                           "limit": 3}"#, all).unwrap(),
+                   concat!(r#"[{"pango_lineage":"B.1.10","country":"Switzerland","count":2},"#,
+                           r#"{"pango_lineage":"B.1.2","country":null,"count":1},"#,
+                           r#"{"pango_lineage":"B.1.2","country":"Germany","count":1}]"#));
+        assert_eq!(run(r#"{"type": "Aggregated", "groupByFields": ["pango_lineage"],
+                           "orderByFields": [{"field": "pango_lineage", "order": "descending"}],
+                           "offset": 1}"#, all).unwrap(),
+                   r#"[{"pango_lineage":"B.1.2","count":3}]"#);
+        assert_eq!(run(r#"{"type": "Aggregated", "groupByFields": ["age"]}"#,
+                       r#"{"type": "StringEquals", "column": "country", "value": "Switzerland"}"#)
+                   .unwrap(), r#"[{"age":3,"count":2},{"age":10,"count":1}]"#);
+        // Ties broken by the group values; the groups come from a
+        // HashMap, thus repeat to catch a random order
+        for _ in 0..20 {
+            assert_eq!(run(r#"{"type": "Aggregated", "groupByFields": ["country"],
+                               "orderByFields": ["count"], "limit": 1}"#, all).unwrap(),
+                       r#"[{"country":null,"count":1}]"#);
+            assert_eq!(run(r#"{"type": "Aggregated", "groupByFields": ["age", "pango_lineage"],
+                               "orderByFields": [{"field": "count", "order": "descending"}],
+                               "offset": 1, "limit": 2}"#, all).unwrap(),
+                       concat!(r#"[{"age":null,"pango_lineage":"B.1.2","count":1},"#,
+                               r#"{"age":10,"pango_lineage":"B.1.2","count":1}]"#));
+        }
+        // No groups without rows, but a count of 0 without groupByFields
+        let none = r#"{"type": "Or", "children": []}"#;
+        assert_eq!(run(r#"{"type": "Aggregated", "groupByFields": ["country"]}"#, none).unwrap(),
+                   "[]");
+        assert_eq!(run(r#"{"type": "Aggregated"}"#, none).unwrap(), r#"[{"count":0}]"#);
+        assert_eq!(
+            format!("{:#}", run(r#"{"type": "Aggregated", "groupByFields": ["country"],
+                                    "orderByFields": ["age"]}"#, all).unwrap_err()),
+            r#"can only order by groupByFields or "count", not "age""#);
+    }
+}
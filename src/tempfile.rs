@@ -1,4 +1,4 @@
-use std::path::{Path, PathBuf};
+use std::{fs::File, io::ErrorKind, path::{Path, PathBuf}};
 
 use tempfile::{NamedTempFile, Builder};
 
@@ -14,7 +14,11 @@ pub enum TempfileError {
     #[error("path is missing filename: {0}")]
     MissingFilename(Box<PathBuf>),
     #[error("could not create tempfile: {0}")]
-    IOError(#[from] Box<std::io::Error>)
+    IOError(#[from] Box<std::io::Error>),
+    #[error("could not make backup {0}: {1}")]
+    BackupError(Box<PathBuf>, Box<std::io::Error>),
+    #[error("could not replace {0}: {1}")]
+    ReplaceError(Box<PathBuf>, Box<std::io::Error>),
 }
 
 pub fn named_tempfile_for<P: AsRef<Path>>(targetfile: P) -> Result<NamedTempFile, TempfileError> {
@@ -30,5 +34,131 @@ pub fn named_tempfile_for<P: AsRef<Path>>(targetfile: P) -> Result<NamedTempFile
 }
 
 
-// now for the more complicated bits?
+/// The path with `.bak` appended to the file name.
+pub fn backup_path_for<P: AsRef<Path>>(targetfile: P) -> PathBuf {
+    let mut s = targetfile.as_ref().as_os_str().to_owned();
+    s.push(".bak");
+    s.into()
+}
+
+/// A tempfile in the same directory as the target file, which
+/// atomically replaces the target file on `commit`. If dropped
+/// without calling `commit`, the tempfile is deleted and the target
+/// file left untouched.
+pub struct ReplacingTempfile {
+    tmp: NamedTempFile,
+    target: PathBuf,
+}
+
+impl ReplacingTempfile {
+    pub fn new<P: AsRef<Path>>(targetfile: P) -> Result<Self, TempfileError> {
+        Ok(ReplacingTempfile {
+            tmp: named_tempfile_for(&targetfile)?,
+            target: targetfile.as_ref().to_owned()
+        })
+    }
+
+    pub fn as_file_mut(&mut self) -> &mut File {
+        self.tmp.as_file_mut()
+    }
+
+    /// fsync the tempfile (makes it slower but safe), then rename it
+    /// to the target path. If the target file exists, its permissions
+    /// are kept; if `backup` is true, it is kept at
+    /// `backup_path_for(target)` (replacing any previous backup).
+    pub fn commit(self, backup: bool) -> Result<(), TempfileError> {
+        let ReplacingTempfile { tmp, target } = self;
+        let replace_error = |e| TempfileError::ReplaceError(
+            target.clone().into(), Box::new(e));
+        match std::fs::metadata(&target) {
+            Ok(m) => tmp.as_file().set_permissions(m.permissions()).map_err(replace_error)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => return Err(replace_error(e)),
+        }
+        tmp.as_file().sync_data().map_err(replace_error)?;
+        if backup {
+            let backup_path = backup_path_for(&target);
+            let backup_error = |e| TempfileError::BackupError(
+                backup_path.clone().into(), Box::new(e));
+            match std::fs::remove_file(&backup_path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(backup_error(e)),
+                _ => ()
+            }
+            // Hard link, so that the target path is never missing;
+            // copy if the file system doesn't support that.
+            if let Err(e) = std::fs::hard_link(&target, &backup_path) {
+                match e.kind() {
+                    ErrorKind::NotFound => (),
+                    _ => {
+                        std::fs::copy(&target, &backup_path).map_err(backup_error)?;
+                    }
+                }
+            }
+        }
+        tmp.persist(&target).map_err(|e| replace_error(e.error))?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::{read_to_string, write}, io::Write};
+
+    use super::*;
 
+    fn replace(target: &Path, contents: &str, backup: bool) {
+        let mut out = ReplacingTempfile::new(target).unwrap();
+        out.as_file_mut().write_all(contents.as_bytes()).unwrap();
+        out.commit(backup).unwrap();
+    }
+
+    #[test]
+    fn t_backup_path_for() {
+        assert_eq!(backup_path_for("a/b.ndjson.gz"), PathBuf::from("a/b.ndjson.gz.bak"));
+    }
+
+    #[test]
+    fn t_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("t.ndjson");
+        let backup = backup_path_for(&target);
+
+        // New file
+        replace(&target, "1", true);
+        assert_eq!(read_to_string(&target).unwrap(), "1");
+        assert!(! backup.exists());
+
+        replace(&target, "2", false);
+        assert_eq!(read_to_string(&target).unwrap(), "2");
+        assert!(! backup.exists());
+
+        replace(&target, "3", true);
+        assert_eq!(read_to_string(&target).unwrap(), "3");
+        assert_eq!(read_to_string(&backup).unwrap(), "2");
+        replace(&target, "4", true);
+        assert_eq!(read_to_string(&backup).unwrap(), "3");
+
+        // Dropped without commit
+        let mut out = ReplacingTempfile::new(&target).unwrap();
+        out.as_file_mut().write_all(b"5").unwrap();
+        drop(out);
+        assert_eq!(read_to_string(&target).unwrap(), "4");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn t_commit_keeps_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("t.json");
+        write(&target, "1").unwrap();
+        std::fs::set_permissions(&target, std::fs::Permissions::from_mode(0o640)).unwrap();
+        replace(&target, "2", true);
+        let mode = |p: &Path| std::fs::metadata(p).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&target), 0o640);
+        assert_eq!(mode(&backup_path_for(&target)), 0o640);
+    }
+}
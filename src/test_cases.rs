@@ -0,0 +1,173 @@
+//! LAPIS/SILO test case files: JSON documents with `testCaseName`,
+//! `query` (see `query::Query`) and `expectedQueryResult`, and the
+//! comparison of results with the expectation.
+
+use std::{io::Write, path::{Path, PathBuf}};
+
+use anyhow::{anyhow, Context, Result};
+use jzon::JsonValue;
+
+use crate::{easyjson::{EasyJsonValue, EasyObject},
+            query::Query,
+            tempfile::ReplacingTempfile};
+
+
+#[derive(Debug)]
+pub struct TestCase {
+    pub path: PathBuf,
+    pub name: String,
+    pub query: Query,
+    pub expected: JsonValue,
+    /// The whole document, for `bless`
+    json: JsonValue,
+}
+
+impl TestCase {
+    pub fn from_file(path: &Path) -> Result<TestCase> {
+        (|| -> Result<_> {
+            let json = jzon::parse(&std::fs::read_to_string(path)?)?;
+            let obj = json.object()?;
+            Ok(TestCase {
+                path: path.to_owned(),
+                name: obj.xget("testCaseName")?.string()?,
+                query: Query::from_json(obj.xget("query")?)?,
+                expected: obj.xget("expectedQueryResult")?.clone(),
+                json,
+            })
+        })().with_context(|| anyhow!("reading test case file {path:?}"))
+    }
+
+    /// Replace the expectation in the file with `result`.
+    pub fn bless(&mut self, result: &JsonValue) -> Result<()> {
+        (|| -> Result<_> {
+            self.json.insert("expectedQueryResult", result.clone())?;
+            let mut out = ReplacingTempfile::new(&self.path)?;
+            writeln!(out.as_file_mut(), "{}", self.json.pretty(2))?;
+            out.commit(false)?;
+            self.expected = result.clone();
+            Ok(())
+        })().with_context(|| anyhow!("writing test case file {:?}", self.path))
+    }
+
+    /// The differences between `result` and the expectation, empty if
+    /// they match, see `diff_results`.
+    pub fn diff(&self, result: &JsonValue) -> Vec<String> {
+        diff_results(&self.expected, result, self.query.is_ordered())
+    }
+}
+
+/// The paths of the `*.json` files in `dir`, sorted.
+pub fn test_case_paths(dir: &Path) -> Result<Vec<PathBuf>> {
+    (|| -> Result<_> {
+        let mut paths = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == "json") {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        Ok(paths)
+    })().with_context(|| anyhow!("reading test case directory {dir:?}"))
+}
+
+/// The differences between the `expected` and `actual` results, as
+/// lines starting with `-` (only in expected) or `+` (only in
+/// actual); empty if they match. Unless `ordered`, array elements are
+/// compared as multisets.
+pub fn diff_results(expected: &JsonValue, actual: &JsonValue, ordered: bool) -> Vec<String> {
+    let mut out = Vec::new();
+    match (expected, actual) {
+        (JsonValue::Array(e), JsonValue::Array(a)) if ordered => {
+            for i in 0..e.len().max(a.len()) {
+                if e.get(i) != a.get(i) {
+                    if let Some(e) = e.get(i) {
+                        out.push(format!("-[{i}] {}", e.dump()));
+                    }
+                    if let Some(a) = a.get(i) {
+                        out.push(format!("+[{i}] {}", a.dump()));
+                    }
+                }
+            }
+        }
+        (JsonValue::Array(e), JsonValue::Array(a)) => {
+            let mut a: Vec<Option<&JsonValue>> = a.iter().map(Some).collect();
+            for e in e {
+                match a.iter_mut().find(|a| **a == Some(e)) {
+                    Some(a) => *a = None,
+                    None => out.push(format!("- {}", e.dump())),
+                }
+            }
+            for a in a.into_iter().flatten() {
+                out.push(format!("+ {}", a.dump()));
+            }
+        }
+        (e, a) => if e != a {
+            out.push(format!("- {}", e.dump()));
+            out.push(format!("+ {}", a.dump()));
+        }
+    }
+    out
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn t_diff_results() {
+        let diff = |e: &str, a: &str, ordered| diff_results(
+            &jzon::parse(e).unwrap(), &jzon::parse(a).unwrap(), ordered);
+        let e = r#"[{"a": 1}, {"a": 2}, {"a": 2}]"#;
+        assert!(diff(e, r#"[{"a": 2}, {"a": 1}, {"a": 2}]"#, false).is_empty());
+        assert_eq!(diff(e, r#"[{"a": 2}, {"a": 1}, {"a": 2}]"#, true),
+                   [r#"-[0] {"a":1}"#, r#"+[0] {"a":2}"#, r#"-[1] {"a":2}"#, r#"+[1] {"a":1}"#]);
+        assert_eq!(diff(e, r#"[{"a": 2}, {"a": 1}, {"a": 3}]"#, false),
+                   [r#"- {"a":2}"#, r#"+ {"a":3}"#]);
+        assert_eq!(diff(e, r#"[{"a": 1}, {"a": 2}]"#, true), [r#"-[2] {"a":2}"#]);
+        assert_eq!(diff(r#"{"count": 1}"#, r#"[{"count": 1}]"#, false),
+                   [r#"- {"count":1}"#, r#"+ [{"count":1}]"#]);
+    }
+
+    #[test]
+    fn t_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("b.json");
+        std::fs::write(&path, r#"{"testCaseName": "all", "query": {
+            "action": {"type": "Aggregated"}, "filterExpression": {"type": "True"}},
+            "expectedQueryResult": [{"count": 1}]}"#).unwrap();
+        std::fs::write(dir.path().join("a.json"), "{}").unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        }
+
+        let paths = test_case_paths(dir.path()).unwrap();
+        assert_eq!(paths, [dir.path().join("a.json"), path.clone()]);
+        assert!(TestCase::from_file(&paths[0]).is_err());
+
+        let mut test_case = TestCase::from_file(&path).unwrap();
+        assert_eq!(test_case.name, "all");
+        assert!(! test_case.query.is_ordered());
+        let result = jzon::parse(r#"[{"count": 2}]"#).unwrap();
+        assert_eq!(test_case.diff(&result), [r#"- {"count":1}"#, r#"+ {"count":2}"#]);
+        test_case.bless(&result).unwrap();
+        assert!(test_case.diff(&result).is_empty());
+
+        let test_case = TestCase::from_file(&path).unwrap();
+        assert!(test_case.diff(&result).is_empty());
+        assert_eq!(test_case.query, Query::parse(r#"{
+            "action": {"type": "Aggregated"}, "filterExpression": {"type": "True"}}"#).unwrap());
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.starts_with("{\n  \"testCaseName\": \"all\",\n"));
+        assert!(text.ends_with("}\n"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o644);
+        }
+    }
+}
@@ -0,0 +1,99 @@
+//! Reading of tab separated files with a header row, without a fixed
+//! schema. The files may be compressed (see `compressed_io`).
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, Context, Result};
+use csv::StringRecord;
+
+use crate::compressed_io::open_reader;
+
+#[derive(Debug)]
+pub struct Tsv {
+    pub path: String,
+    pub headers: StringRecord,
+    pub rows: Vec<StringRecord>,
+}
+
+impl Tsv {
+    pub fn from_file(path: &str) -> Result<Tsv> {
+        (|| -> Result<_> {
+            let inp = open_reader(path)?;
+            let mut rdr = csv::ReaderBuilder::new()
+                .delimiter(b'\t')
+                .has_headers(true)
+                // .trim(csv::Trim::All)
+                .from_reader(inp);
+            let headers = rdr.headers()?.clone();
+            let rows = rdr.records().collect::<Result<Vec<_>, _>>()?;
+            Ok(Tsv { path: path.into(), headers, rows })
+        })().with_context(|| anyhow!("reading tsv file {path:?}"))
+    }
+
+    /// The 1-based line number in the file at which row `rowi`
+    /// starts, counting the header line.
+    pub fn line_number(&self, rowi: usize) -> u64 {
+        // Rows not read from a file have no position
+        self.rows[rowi].position().map_or(rowi as u64 + 2, |p| p.line())
+    }
+
+    pub fn column_index(&self, column: &str) -> Result<usize> {
+        self.headers.iter().position(|h| h == column).ok_or_else(
+            || anyhow!("missing column {column:?} in {:?}", self.path))
+    }
+
+    /// Map the values in `column` to the row index, verifying that
+    /// the values are unique.
+    pub fn index_by(&self, column: &str) -> Result<HashMap<&str, usize>> {
+        let i = self.column_index(column)?;
+        let mut index = HashMap::new();
+        for (rowi, row) in self.rows.iter().enumerate() {
+            let key = &row[i];
+            if let Some(old) = index.insert(key, rowi) {
+                bail!("duplicate entry for {column:?} {key:?} in {:?}: {:?}",
+                      self.path, &self.rows[old])
+            }
+        }
+        Ok(index)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tsv(rows: &[&[&str]]) -> Tsv {
+        Tsv {
+            path: "t.tsv".into(),
+            headers: StringRecord::from(rows[0].to_vec()),
+            rows: rows[1..].iter().map(|r| StringRecord::from(r.to_vec())).collect(),
+        }
+    }
+
+    #[test]
+    fn t_index_by() {
+        let t = tsv(&[&["id", "x"], &["E1", "a"], &["E2", "a"]]);
+        assert_eq!(t.column_index("x").unwrap(), 1);
+        assert!(t.column_index("y").is_err());
+        let index = t.index_by("id").unwrap();
+        assert_eq!(index.len(), 2);
+        assert_eq!(index["E2"], 1);
+        let e = t.index_by("x").unwrap_err();
+        assert_eq!(e.to_string(),
+                   r#"duplicate entry for "x" "a" in "t.tsv": StringRecord(["E1", "a"])"#);
+        assert!(t.index_by("y").is_err());
+        assert_eq!(t.line_number(1), 3);
+    }
+
+    #[test]
+    fn t_line_number() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("t.tsv");
+        std::fs::write(&path, "id\tx\nE1\t\"a\nb\"\nE2\tc\n").unwrap();
+        let t = Tsv::from_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(&t.rows[0][1], "a\nb");
+        assert_eq!(t.line_number(0), 2);
+        assert_eq!(t.line_number(1), 4);
+    }
+}
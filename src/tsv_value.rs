@@ -0,0 +1,501 @@
+//! Conversion of TSV cell strings to typed values, either with a
+//! given type or inferred from the string.
+
+use std::{cmp::Ordering, fmt::Display, hash::{Hash, Hasher}};
+
+use chrono::NaiveDate;
+use jzon::JsonValue;
+use kstring::KString;
+
+use crate::{pangolineage::{LineageName, LineageParseError},
+            tsv::Tsv};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColumnType {
+    Bool,
+    Int,
+    Float,
+    String,
+    /// ISO 8601 date, `YYYY-MM-DD`
+    Date,
+    /// Pango lineage name, e.g. `BA.2.75`
+    Lineage,
+    /// Infer the type of each cell separately, see
+    /// `TsvValueParser::infer`
+    Auto,
+}
+
+impl ColumnType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ColumnType::Bool => "bool",
+            ColumnType::Int => "int",
+            ColumnType::Float => "float",
+            ColumnType::String => "string",
+            ColumnType::Date => "date",
+            ColumnType::Lineage => "lineage",
+            ColumnType::Auto => "auto",
+        }
+    }
+}
+
+impl Display for ColumnType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+
+#[derive(Debug, Clone)]
+pub enum TsvValue {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Date(NaiveDate),
+    /// Also used for lineage names (after validation)
+    String(KString),
+}
+
+impl TsvValue {
+    pub fn is_null(&self) -> bool {
+        matches!(self, TsvValue::Null)
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        match self {
+            TsvValue::Null => JsonValue::Null,
+            TsvValue::Bool(v) => (*v).into(),
+            TsvValue::Int(v) => (*v).into(),
+            TsvValue::Float(v) => (*v).into(),
+            TsvValue::Date(v) => v.format("%Y-%m-%d").to_string().into(),
+            TsvValue::String(v) => v.as_str().into(),
+        }
+    }
+
+    /// Null first, ints and floats compared numerically (exactly, and
+    /// `-0.0` equal to `0.0`; NaN after all numbers, or before with
+    /// the sign bit set), values of different types in the order of
+    /// the variants. This is also the equality of `TsvValue`.
+    pub fn total_cmp(&self, other: &TsvValue) -> Ordering {
+        use TsvValue::*;
+        let rank = |v: &TsvValue| match v {
+            Null => 0,
+            Bool(_) => 1,
+            Int(_) | Float(_) => 2,
+            Date(_) => 3,
+            String(_) => 4,
+        };
+        match (self, other) {
+            (Bool(a), Bool(b)) => a.cmp(b),
+            (Int(a), Int(b)) => a.cmp(b),
+            (Float(a), Float(b)) => if a == b { Ordering::Equal } else { a.total_cmp(b) },
+            (Int(a), Float(b)) => cmp_int_float(*a, *b),
+            (Float(a), Int(b)) => cmp_int_float(*b, *a).reverse(),
+            (Date(a), Date(b)) => a.cmp(b),
+            (String(a), String(b)) => a.cmp(b),
+            (a, b) => rank(a).cmp(&rank(b)),
+        }
+    }
+}
+
+/// The integral value of `v` if it has one that fits into an i64.
+fn float_as_i64(v: f64) -> Option<i64> {
+    // -2^63 and 2^63 are exact as f64
+    if v.fract() == 0.0 && (-9223372036854775808.0..9223372036854775808.0).contains(&v) {
+        Some(v as i64)
+    } else {
+        None
+    }
+}
+
+fn cmp_int_float(a: i64, b: f64) -> Ordering {
+    if b.is_nan() {
+        return if b.is_sign_negative() { Ordering::Greater } else { Ordering::Less }
+    }
+    if b >= 9223372036854775808.0 {
+        Ordering::Less
+    } else if b < -9223372036854775808.0 {
+        Ordering::Greater
+    } else {
+        // b.trunc() fits, compare the fraction if the integral parts
+        // are equal
+        a.cmp(&(b.trunc() as i64)).then_with(
+            || 0.0.partial_cmp(&b.fract()).expect("not NaN"))
+    }
+}
+
+impl PartialEq for TsvValue {
+    fn eq(&self, other: &TsvValue) -> bool {
+        self.total_cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for TsvValue {}
+
+impl Hash for TsvValue {
+    /// Consistent with `total_cmp`: numbers with an integral value
+    /// hash the same as ints.
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            TsvValue::Null => 0u8.hash(state),
+            TsvValue::Bool(v) => (1u8, v).hash(state),
+            TsvValue::Int(v) => (2u8, v).hash(state),
+            TsvValue::Float(v) => match float_as_i64(*v) {
+                Some(i) => (2u8, i).hash(state),
+                None => (3u8, v.to_bits()).hash(state),
+            },
+            TsvValue::Date(v) => (4u8, v).hash(state),
+            TsvValue::String(v) => (5u8, v).hash(state),
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ParseMode {
+    /// Values must be in the exact canonical form of their type
+    #[default]
+    Strict,
+    /// Surrounding whitespace is ignored, bools and null tokens are
+    /// matched case-insensitively, and values that still can't be
+    /// parsed as their type become null (see
+    /// `TsvValueParser::warn_invalid`)
+    Lenient,
+}
+
+
+/// `line` is the 1-based line number in the file, counting the header
+/// line (see `Tsv::line_number`).
+#[derive(Debug, thiserror::Error)]
+pub enum TsvValueError {
+    #[error("invalid {expected} value {raw:?} in column {column:?}, line {line}")]
+    Invalid {
+        column: String,
+        line: u64,
+        raw: String,
+        expected: ColumnType,
+    },
+    #[error("invalid lineage value {raw:?} in column {column:?}, line {line}: {source}")]
+    Lineage {
+        column: String,
+        line: u64,
+        raw: String,
+        source: LineageParseError,
+    },
+}
+
+
+#[derive(Debug, Clone)]
+pub struct TsvValueParser {
+    /// Cell strings that represent null, for all types
+    pub null_tokens: Vec<KString>,
+    pub mode: ParseMode,
+}
+
+impl Default for TsvValueParser {
+    /// Only the empty string is null, strict mode.
+    fn default() -> Self {
+        TsvValueParser {
+            null_tokens: vec![KString::from_static("")],
+            mode: ParseMode::Strict,
+        }
+    }
+}
+
+fn parse_bool(s: &str, mode: ParseMode) -> Option<bool> {
+    match s {
+        "true" => Some(true),
+        "false" => Some(false),
+        _ => match mode {
+            ParseMode::Strict => None,
+            ParseMode::Lenient =>
+                if s.eq_ignore_ascii_case("true") {
+                    Some(true)
+                } else if s.eq_ignore_ascii_case("false") {
+                    Some(false)
+                } else {
+                    None
+                }
+        }
+    }
+}
+
+fn parse_float(s: &str) -> Option<f64> {
+    // Rust accepts "inf" and "NaN", JSON can't represent them
+    s.parse::<f64>().ok().filter(|v| v.is_finite())
+}
+
+fn parse_date(s: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(s, "%Y-%m-%d").ok()
+}
+
+/// Withdrawn names keep their `*` prefix. Lenient mode accepts
+/// lowercase base names.
+fn parse_lineage(s: &str, mode: ParseMode) -> Result<KString, LineageParseError> {
+    let l = LineageName::parse(s, mode == ParseMode::Lenient)?;
+    Ok(KString::from_string(l.to_string()))
+}
+
+impl TsvValueParser {
+    pub fn new(null_tokens: Vec<KString>, mode: ParseMode) -> Self {
+        TsvValueParser { null_tokens, mode }
+    }
+
+    fn prepare<'s>(&self, raw: &'s str) -> &'s str {
+        match self.mode {
+            ParseMode::Strict => raw,
+            ParseMode::Lenient => raw.trim(),
+        }
+    }
+
+    fn is_null_prepared(&self, s: &str) -> bool {
+        match self.mode {
+            ParseMode::Strict =>
+                self.null_tokens.iter().any(|t| t.as_str() == s),
+            ParseMode::Lenient =>
+                self.null_tokens.iter().any(|t| t.eq_ignore_ascii_case(s)),
+        }
+    }
+
+    pub fn is_null(&self, raw: &str) -> bool {
+        self.is_null_prepared(self.prepare(raw))
+    }
+
+    /// Parse `raw` as `column_type`; `column` and `line` are only used
+    /// for the error message.
+    pub fn parse(
+        &self, column_type: ColumnType, raw: &str, column: &str, line: u64
+    ) -> Result<TsvValue, TsvValueError> {
+        let s = self.prepare(raw);
+        if self.is_null_prepared(s) {
+            return Ok(TsvValue::Null)
+        }
+        let v = match column_type {
+            ColumnType::Bool => parse_bool(s, self.mode).map(TsvValue::Bool),
+            ColumnType::Int => s.parse().ok().map(TsvValue::Int),
+            ColumnType::Float => parse_float(s).map(TsvValue::Float),
+            ColumnType::String => Some(TsvValue::String(KString::from_ref(s))),
+            ColumnType::Date => parse_date(s).map(TsvValue::Date),
+            ColumnType::Lineage => match (parse_lineage(s, self.mode), self.mode) {
+                (Ok(l), _) => Some(TsvValue::String(l)),
+                (Err(_), ParseMode::Lenient) => None,
+                (Err(source), ParseMode::Strict) => return Err(TsvValueError::Lineage {
+                    column: column.into(),
+                    line,
+                    raw: raw.into(),
+                    source,
+                }),
+            },
+            ColumnType::Auto => Some(self.infer_prepared(s)),
+        };
+        match (v, self.mode) {
+            (Some(v), _) => Ok(v),
+            (None, ParseMode::Lenient) => Ok(TsvValue::Null),
+            (None, ParseMode::Strict) => Err(TsvValueError::Invalid {
+                column: column.into(),
+                line,
+                raw: raw.into(),
+                expected: column_type,
+            })
+        }
+    }
+
+    /// Like `parse`, but returning JSON.
+    pub fn parse_json(
+        &self, column_type: ColumnType, raw: &str, column: &str, line: u64
+    ) -> Result<JsonValue, TsvValueError> {
+        Ok(self.parse(column_type, raw, column, line)?.to_json())
+    }
+
+    /// The number of `values` that lenient mode turns into null
+    /// because they can't be parsed as `column_type` (always 0 in
+    /// strict mode, where they are errors).
+    pub fn count_invalid<'v>(
+        &self, column_type: ColumnType, values: impl Iterator<Item = &'v str>
+    ) -> usize {
+        if self.mode == ParseMode::Strict {
+            return 0
+        }
+        values.filter(|raw| ! self.is_null(raw)
+                      && self.parse(column_type, raw, "", 0).is_ok_and(|v| v.is_null()))
+            .count()
+    }
+
+    /// Warn on stderr if lenient mode turns values of column `coli`
+    /// of `tsv` into null, see `count_invalid`.
+    pub fn warn_invalid(&self, column_type: ColumnType, tsv: &Tsv, coli: usize) {
+        let n = self.count_invalid(column_type, tsv.rows.iter().map(|r| &r[coli]));
+        if n > 0 {
+            eprintln!("warning: {n} invalid {column_type} values in column {:?} \
+                       of {:?} treated as null", &tsv.headers[coli], tsv.path);
+        }
+    }
+
+    fn infer_prepared(&self, s: &str) -> TsvValue {
+        if self.is_null_prepared(s) {
+            TsvValue::Null
+        } else if let Some(v) = parse_bool(s, self.mode) {
+            TsvValue::Bool(v)
+        } else if let Ok(v) = s.parse() {
+            TsvValue::Int(v)
+        } else if let Some(v) = parse_float(s) {
+            TsvValue::Float(v)
+        } else if let Some(v) = parse_date(s) {
+            TsvValue::Date(v)
+        } else {
+            TsvValue::String(KString::from_ref(s))
+        }
+    }
+
+    /// Determine the type from the string: null, bool, int, float,
+    /// date, or else string (lineages are not detected). Never fails.
+    pub fn infer(&self, raw: &str) -> TsvValue {
+        self.infer_prepared(self.prepare(raw))
+    }
+
+    /// The most specific type that all non-null `values` can be
+    /// parsed as (ints are accepted as floats). Columns with only
+    /// null values are `String`.
+    pub fn infer_column_type<'v>(&self, values: impl Iterator<Item = &'v str>) -> ColumnType {
+        let mut t: Option<ColumnType> = None;
+        for raw in values {
+            let vt = match self.infer(raw) {
+                TsvValue::Null => continue,
+                TsvValue::Bool(_) => ColumnType::Bool,
+                TsvValue::Int(_) => ColumnType::Int,
+                TsvValue::Float(_) => ColumnType::Float,
+                TsvValue::Date(_) => ColumnType::Date,
+                TsvValue::String(_) => ColumnType::String,
+            };
+            t = Some(match (t, vt) {
+                (None, vt) => vt,
+                (Some(t), vt) if t == vt => t,
+                (Some(ColumnType::Int), ColumnType::Float)
+                    | (Some(ColumnType::Float), ColumnType::Int) => ColumnType::Float,
+                _ => return ColumnType::String,
+            });
+        }
+        t.unwrap_or(ColumnType::String)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parser(mode: ParseMode) -> TsvValueParser {
+        TsvValueParser::new(
+            ["", "NA", "null", "-"].iter().map(|s| KString::from_static(s)).collect(),
+            mode)
+    }
+
+    #[test]
+    fn t_parse_strict() {
+        let p = parser(ParseMode::Strict);
+        let parse = |t, s| p.parse(t, s, "c", 1).map(|v| v.to_json().dump());
+        assert_eq!(parse(ColumnType::Bool, "true").unwrap(), "true");
+        assert_eq!(parse(ColumnType::Bool, "NA").unwrap(), "null");
+        assert_eq!(parse(ColumnType::Int, "-").unwrap(), "null");
+        assert_eq!(parse(ColumnType::Int, "-12").unwrap(), "-12");
+        assert_eq!(parse(ColumnType::Float, "0.5").unwrap(), "0.5");
+        assert_eq!(parse(ColumnType::Date, "2021-03-18").unwrap(), "\"2021-03-18\"");
+        assert_eq!(parse(ColumnType::Lineage, "BA.2.75").unwrap(), "\"BA.2.75\"");
+        assert_eq!(parse(ColumnType::Lineage, "*J.1").unwrap(), "\"*J.1\"");
+        assert_eq!(parse(ColumnType::String, "NA").unwrap(), "null");
+        assert_eq!(parse(ColumnType::Auto, "12").unwrap(), "12");
+
+        let e = parse(ColumnType::Bool, "True").unwrap_err();
+        assert_eq!(e.to_string(), "invalid bool value \"True\" in column \"c\", line 1");
+        assert!(parse(ColumnType::Int, " 1").is_err());
+        assert!(parse(ColumnType::Int, "1.5").is_err());
+        assert!(parse(ColumnType::Float, "NaN").is_err());
+        assert!(parse(ColumnType::Date, "2021-02-30").is_err());
+
+        let e = p.parse(ColumnType::Lineage, "B..1", "c", 3).unwrap_err();
+        assert!(matches!(&e, TsvValueError::Lineage {
+            source: LineageParseError::EmptyComponent(l), line: 3, ..
+        } if l == "B..1"));
+        assert_eq!(e.to_string(), "invalid lineage value \"B..1\" in column \"c\", line 3: \
+                                   empty path component in lineage \"B..1\"");
+        assert!(matches!(p.parse(ColumnType::Lineage, "ba.2", "c", 1),
+                         Err(TsvValueError::Lineage { source: LineageParseError::Lowercase(_), .. })));
+    }
+
+    #[test]
+    fn t_parse_lenient() {
+        let p = parser(ParseMode::Lenient);
+        let parse = |t, s| p.parse(t, s, "c", 1).unwrap();
+        assert_eq!(parse(ColumnType::Bool, " True"), TsvValue::Bool(true));
+        assert_eq!(parse(ColumnType::Bool, "Null"), TsvValue::Null);
+        assert_eq!(parse(ColumnType::Int, " 1 "), TsvValue::Int(1));
+        assert_eq!(parse(ColumnType::Int, "x"), TsvValue::Null);
+        assert_eq!(parse(ColumnType::Lineage, "ba.2 "), TsvValue::String("BA.2".into()));
+        assert_eq!(parse(ColumnType::Lineage, "*j.1"), TsvValue::String("*J.1".into()));
+
+        let values = ["1", "x", "NA", " 2", "", "1.5"];
+        assert_eq!(p.count_invalid(ColumnType::Int, values.iter().copied()), 2);
+        assert_eq!(p.count_invalid(ColumnType::String, values.iter().copied()), 0);
+        assert_eq!(parser(ParseMode::Strict).count_invalid(
+            ColumnType::Int, values.iter().copied()), 0);
+    }
+
+    #[test]
+    fn t_infer() {
+        let p = parser(ParseMode::Strict);
+        assert_eq!(p.infer("NA"), TsvValue::Null);
+        assert_eq!(p.infer("false"), TsvValue::Bool(false));
+        assert_eq!(p.infer("7"), TsvValue::Int(7));
+        assert_eq!(p.infer("7.25"), TsvValue::Float(7.25));
+        assert_eq!(p.infer("2020-01-01"),
+                   TsvValue::Date(NaiveDate::from_ymd_opt(2020, 1, 1).unwrap()));
+        assert_eq!(p.infer("B.1"), TsvValue::String("B.1".into()));
+
+        let col = |vals: &[&str]| p.infer_column_type(vals.iter().copied());
+        assert_eq!(col(&["1", "", "2"]), ColumnType::Int);
+        assert_eq!(col(&["1", "NA", "2.5"]), ColumnType::Float);
+        assert_eq!(col(&["2020-01-01", "-"]), ColumnType::Date);
+        assert_eq!(col(&["1", "true"]), ColumnType::String);
+        assert_eq!(col(&["", ""]), ColumnType::String);
+    }
+
+    #[test]
+    fn t_total_cmp() {
+        let p = parser(ParseMode::Strict);
+        let mut vals: Vec<_> = ["b", "10", "NA", "2.5", "2020-01-01", "a", "true", "2", "false"]
+            .iter().map(|s| p.infer(s)).collect();
+        vals.sort_by(TsvValue::total_cmp);
+        assert_eq!(vals.iter().map(|v| v.to_json().dump()).collect::<Vec<_>>(),
+                   ["null", "false", "true", "2", "2.5", "10", "\"2020-01-01\"", "\"a\"", "\"b\""]);
+    }
+
+    #[test]
+    fn t_eq_hash() {
+        use std::collections::HashSet;
+        use TsvValue::*;
+
+        assert_eq!(Int(3), Float(3.0));
+        assert_eq!(Float(-0.0), Float(0.0));
+        assert_eq!(Float(-0.0), Int(0));
+        assert_eq!(Float(f64::NAN), Float(f64::NAN));
+        assert_ne!(Int(3), Float(3.5));
+        assert_ne!(Int(i64::MAX), Float(9223372036854775808.0));
+        assert_ne!(Int(9007199254740993), Float(9007199254740992.0));
+        assert_ne!(Int(1), String("1".into()));
+        assert_eq!(Int(-1).total_cmp(&Float(-0.5)), Ordering::Less);
+        assert_eq!(Float(-1.5).total_cmp(&Int(-1)), Ordering::Less);
+        assert_eq!(Float(f64::INFINITY).total_cmp(&Float(f64::NAN)), Ordering::Less);
+        assert_eq!(Int(i64::MAX).total_cmp(&Float(f64::NAN)), Ordering::Less);
+        assert_eq!(Float(-f64::NAN).total_cmp(&Int(i64::MIN)), Ordering::Less);
+
+        let set: HashSet<TsvValue> = vec![
+            Int(3), Float(3.0), Float(-0.0), Int(0), Float(0.0), Float(f64::NAN),
+            Float(f64::NAN), Float(0.5), Null, Null, Bool(true),
+        ].into_iter().collect();
+        assert_eq!(set.len(), 6);
+    }
+}